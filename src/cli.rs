@@ -0,0 +1,555 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{error::ErrorKind, parser::ValueSource, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+use mrvl_fw_dloader::{CrcVariant, DownloadOptions, OutPadding, FWSyncHeader, UsbAckPkt};
+
+use crate::{config::{self, Config}, Args, DeviceFilter};
+
+const FW_DIR_HELP: &str = "Pass - as fw filepath to read the firmware from stdin, or an http:// or https://
+URL to fetch it.
+
+With --fw-dir, the image is picked from <dir> by the detected chip and revision,
+as <chip>_<rev>.bin (e.g. 88w8897_b0.bin) and else <chip>.bin, each optionally
+gzipped as .bin.gz. Unknown chips use their hex pid as <chip>, unknown
+revisions their hex id as <rev>.
+
+Several fw files are downloaded in the given order over the same claimed interface,
+each as a complete download from seq 0 ending at its own last block. --start-seq
+only applies to the first, --image-delay and --rehandshake go between them.
+
+Each fw file is taken as one complete image: the block format has no region
+boundaries, so WiFi+Bluetooth combo firmware must come pre-merged (as the
+vendor ships it) or as separate files in the order the chip wants them.
+--region-order names what each file holds, for now only to label the log.";
+
+/** Exit code of command line and config file errors, apart from the device-not-found 2 clap would use */
+pub const EXIT_USAGE: i32 = 64;
+
+pub const EXIT_CODES_HELP: &str = "Exit codes:
+    0  success
+    1  other failure, e.g. a selftest check
+    2  no (unambiguous) device found, or unhandled product id
+    3  USB error: transfers, claiming, endpoints, retries or deadline exhausted,
+       device not ready after flashing
+    4  firmware file: unreadable, invalid, truncated, SHA-256 or block CRC mismatch
+    5  protocol: block rejected, sequence mismatch, short response, --strict violation
+   64  usage error: invalid or conflicting arguments, unreadable or invalid config file
+  130  interrupted by Ctrl-C
+With --all and --repeat, the code of the first failed download.";
+
+/** Firmware downloader for Marvell Avastar 88W8897 / 88W8782U USB adapters */
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = EXIT_CODES_HELP)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/** Options shared by every subcommand */
+#[derive(Debug, clap::Args)]
+pub struct GlobalArgs {
+    /** Scan for devices with vendor id <hex>, for rebadged modules. Combine with --pid if their pid is unknown too */
+    #[arg(long, global = true, value_name = "hex", value_parser = parse_hex_u16, default_value = "1286")]
+    pub vendor: u16,
+    /** Only use devices on USB bus <n> */
+    #[arg(long, global = true, value_name = "n")]
+    pub bus: Option<u8>,
+    /** Only use the device with USB address <n> */
+    #[arg(long, global = true, value_name = "n")]
+    pub address: Option<u8>,
+    /** Only use devices with product id <hex>, unknown pids are flashed like the 88W8897 */
+    #[arg(long, global = true, value_name = "hex", value_parser = parse_hex_u16)]
+    pub pid: Option<u16>,
+    /** Bulk transfer timeout in ms, default 100. Higher values detect failures slower but help flaky links */
+    #[arg(long, global = true, value_name = "ms")]
+    pub timeout: Option<u64>,
+    /** Only log errors, still prints the failure reason */
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /** Log at info (-v) or debug (-vv) level. Takes precedence over RUST_LOG */
+    #[arg(short, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    /** TOML file with defaults for timeout, retries, interface and vendor, instead of ~/.config/marvellfw.toml. Flags given on the command line override the file, the file overrides the built-in defaults */
+    #[arg(long, global = true, value_name = "path")]
+    pub config: Option<PathBuf>,
+    /** Have libusb print its own diagnostics up to <level>, errors and warnings to stderr and the rest to stdout. E.g. to tell a stall from a timeout */
+    #[arg(long, global = true, value_name = "level")]
+    pub libusb_debug: Option<LibusbLogLevel>,
+}
+
+/** Part of a combo chip's firmware a file holds, see `--region-order` */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Region {
+    /** The WiFi firmware, or a pre-merged combo image */
+    Wifi,
+    /** The Bluetooth firmware */
+    Bt,
+}
+
+/** libusb's own log levels, each includes the ones above it */
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LibusbLogLevel {
+    /** Nothing, libusb's default */
+    None,
+    /** Errors only */
+    Error,
+    /** Errors and warnings */
+    Warning,
+    /** Informational messages, e.g. device and transfer setup */
+    Info,
+    /** Everything, including every transfer */
+    Debug,
+}
+
+impl LibusbLogLevel {
+    pub fn level(self) -> rusb::LogLevel {
+        match self {
+            LibusbLogLevel::None => rusb::LogLevel::None,
+            LibusbLogLevel::Error => rusb::LogLevel::Error,
+            LibusbLogLevel::Warning => rusb::LogLevel::Warning,
+            LibusbLogLevel::Info => rusb::LogLevel::Info,
+            LibusbLogLevel::Debug => rusb::LogLevel::Debug,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /** Download firmware to a connected adapter */
+    #[command(after_help = FW_DIR_HELP)]
+    Flash(Box<FlashArgs>),
+    /** List connected Marvell devices, without flashing */
+    List,
+    /** Print the USB descriptor tree of the selected device, e.g. to check the endpoint detection. Nothing is claimed */
+    PrintDescriptors,
+    /** Open the selected device, claim its interface and run the chip-rev handshake, then release it. Sends no firmware */
+    Probe(ProbeArgs),
+    /** Print a table of the firmware's block headers, without touching USB */
+    Inspect(FwArgs),
+    /** Parse the firmware and verify block CRCs, without touching USB */
+    DryRun(FwArgs),
+    /** Check the parser, CRCs and hexdump against an embedded sample image, without touching USB */
+    Selftest,
+}
+
+/** Options of the subcommands that only read a firmware image */
+#[derive(Debug, clap::Args)]
+pub struct FwArgs {
+    /** Firmware file, - for stdin, or an http(s) URL */
+    pub fw: String,
+    /** Expected SHA-256 of the firmware (after gunzip), a mismatch prints the computed digest */
+    #[arg(long, visible_alias = "fw-sha256", value_name = "hex", value_parser = parse_sha256)]
+    pub sha256: Option<[u8; 32]>,
+    /** Write the firmware as read (fetched and gunzipped), i.e. exactly what gets parsed and flashed, to <path> */
+    #[arg(long, value_name = "path")]
+    pub dump_firmware: Option<PathBuf>,
+    /** Algorithm of the block header CRCs, ieee or mpeg2 */
+    #[arg(long, value_name = "variant", default_value = "ieee")]
+    pub crc_variant: CrcVariant,
+    /** Fail on blocks without data instead of warning */
+    #[arg(long)]
+    pub strict: bool,
+    /** Heuristically check the blocks' base_addr progression and warn on overlaps or wild jumps, a hint that the image is misparsed. Errors with --strict */
+    #[arg(long)]
+    pub check_base_addr: bool,
+}
+
+/** Options of `probe`, the subset of `flash` ones that apply before any firmware is sent */
+#[derive(Debug, clap::Args)]
+pub struct ProbeArgs {
+    /** USB interface to claim */
+    #[arg(long, value_name = "n", default_value_t = 0)]
+    pub interface: u8,
+    /** Select alternate setting <n> of the interface after claiming it, default is to leave it unchanged */
+    #[arg(long, value_name = "n")]
+    pub alt_setting: Option<u8>,
+    /** Detach a kernel driver bound to the interface before claiming it */
+    #[arg(long)]
+    pub detach: bool,
+    /** Skip the chip-rev handshake, only open and claim */
+    #[arg(long)]
+    pub no_chip_rev: bool,
+    /** Fail instead of warning on an unknown chip revision, a chip-rev response without the extended ack or with an unexpected seq, the host losing the ack-winner race, and undetectable bulk endpoints */
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct FlashArgs {
+    #[command(flatten)]
+    pub fw: FlashFwArgs,
+    /** Expected SHA-256 of the firmware (after gunzip), a mismatch prints the computed digest */
+    #[arg(long, visible_alias = "fw-sha256", value_name = "hex", value_parser = parse_sha256)]
+    pub sha256: Option<[u8; 32]>,
+    /** Write the firmware as read (fetched and gunzipped), i.e. exactly what gets parsed and flashed, to <path> */
+    #[arg(long, value_name = "path")]
+    pub dump_firmware: Option<PathBuf>,
+    /** Algorithm of the block header CRCs, ieee or mpeg2 */
+    #[arg(long, value_name = "variant", default_value = "ieee")]
+    pub crc_variant: CrcVariant,
+    /** Send blocks failing their header CRC anyway, with a warning. -vv logs every block's declared and computed CRC. Ignored with --strict */
+    #[arg(long)]
+    pub allow_crc_mismatch: bool,
+    /** Fail instead of warning on firmware blocks without data, a device already running its firmware, an unknown chip revision, a chip-rev response without the extended ack or with an unexpected seq, the host losing the ack-winner race, undetectable bulk endpoints, a missing ack for the last block, and an image not starting with the command the chip's firmware does. Rejected blocks always fail, known sync code or not */
+    #[arg(long)]
+    pub strict: bool,
+    /** Heuristically check the blocks' base_addr progression and warn on overlaps or wild jumps, a hint that the image is misparsed. Errors with --strict */
+    #[arg(long)]
+    pub check_base_addr: bool,
+    /** Print a JSON report of the download instead of log lines */
+    #[arg(long)]
+    pub json: bool,
+    /** Append every sync response to <path> as CSV (timestamp_ms,seq,cmd), e.g. to attach to a bug report */
+    #[arg(long, value_name = "path")]
+    pub sync_log: Option<PathBuf>,
+    /** Flash every matching device, then print a per-device summary. Stops starting new devices after a failure unless --keep-going */
+    #[arg(long)]
+    pub all: bool,
+    /** With --all, flash up to <n> devices at once. Log lines are tagged with the device's bus:address */
+    #[arg(long, value_name = "n", default_value_t = 1, requires = "all")]
+    pub jobs: usize,
+    /** Flash the first matching device <n> times for soak testing, then print a summary. Between runs the adapter has to return to download mode (e.g. by power cycling it), which is waited for up to 30s or --wait */
+    #[arg(long, value_name = "n", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "all")]
+    pub repeat: u32,
+    /** With --all or --repeat, carry on after a failed device or run instead of stopping, and report every result. Under --strict a strict violation counts as a failure too. The exit code is 1 if anything failed either way */
+    #[arg(long)]
+    pub keep_going: bool,
+    /** Keep scanning for up to <s> seconds until a device shows up */
+    #[arg(long, value_name = "s", default_value_t = 0)]
+    pub wait: u64,
+    /** After flashing, wait up to <s> seconds for the device to re-enumerate under its runtime pid and log whether it did. The download still counts as a success if it didn't */
+    #[arg(long, value_name = "s")]
+    pub wait_ready: Option<u64>,
+    /** Like --wait-ready, but fails the download if the device isn't back in firmware-ready mode after <s> seconds, or if the chip's runtime pid is unknown */
+    #[arg(long, value_name = "s")]
+    pub verify: Option<u64>,
+    /** After flashing, wait up to <s> seconds for the device to answer a GET_DESCRIPTOR request under its runtime pid */
+    #[arg(long, value_name = "s")]
+    pub confirm_reboot: Option<u64>,
+    /** Attempts per firmware block, default 3 */
+    #[arg(long, value_name = "n", value_parser = clap::value_parser!(u8).range(1..))]
+    pub retries: Option<u8>,
+    /** Skip the blocks before sequence number <n>. A debugging aid, the bootloader normally expects the full sequence */
+    #[arg(long, value_name = "n", default_value_t = 0)]
+    pub start_seq: u32,
+    /** Experimental: keep several blocks in flight instead of waiting for each ack. Failed blocks aren't retried, and --deadline, --stall-timeout and --block-delay don't apply */
+    #[arg(long)]
+    pub pipelined: bool,
+    /** Attempts at the chip-rev handshake, default 3 */
+    #[arg(long, value_name = "n", value_parser = clap::value_parser!(u8).range(1..))]
+    pub chip_rev_retries: Option<u8>,
+    /** Give up if no block gets acked for <s> seconds, e.g. when retries keep failing without the sequence advancing */
+    #[arg(long, value_name = "s")]
+    pub stall_timeout: Option<u64>,
+    /** Give up if the block download takes longer than <s> seconds in total */
+    #[arg(long, value_name = "s")]
+    pub deadline: Option<u64>,
+    /** Delay after a failed attempt, doubling on each further failure of the same block, default 100 */
+    #[arg(long, value_name = "ms")]
+    pub retry_base_ms: Option<u64>,
+    /** Cap for the retry delay, default 100 */
+    #[arg(long, value_name = "ms")]
+    pub retry_max_ms: Option<u64>,
+    /** Pause after every acked block. Slows the download down but can stabilize flaky USB links */
+    #[arg(long, value_name = "ms")]
+    pub block_delay: Option<u64>,
+    /** With several firmware files, pause <ms> between them */
+    #[arg(long, value_name = "ms", default_value_t = 0)]
+    pub image_delay: u64,
+    /** With several firmware files, repeat the chip-rev handshake before each one after the first */
+    #[arg(long)]
+    pub rehandshake: bool,
+    /** Region each firmware file holds, one per file in download order, e.g. wifi,bt for combo chips */
+    #[arg(long, value_name = "regions", value_delimiter = ',')]
+    pub region_order: Vec<Region>,
+    /** Bulk OUT endpoint to use instead of the detected one (or 0x01), bit 7 must be clear */
+    #[arg(long, value_name = "hex", value_parser = parse_hex_u8)]
+    pub endpoint_out: Option<u8>,
+    /** Bulk IN endpoint to use instead of the detected one (or 0x81), bit 7 must be set */
+    #[arg(long, value_name = "hex", value_parser = parse_hex_u8)]
+    pub endpoint_in: Option<u8>,
+    /** Zero pad every bulk OUT transfer to a multiple of the OUT endpoint's max packet size, for bootloaders that stall on short packets. Breaks ones that check the exact length. A block padded past the 620 byte transmit buffer fails the download */
+    #[arg(long)]
+    pub pad: bool,
+    /** Like --pad, but to a multiple of <bytes> instead of the max packet size */
+    #[arg(long, value_name = "bytes", conflicts_with = "pad", value_parser = clap::value_parser!(u32).range(1..))]
+    pub pad_to: Option<u32>,
+    /** Send a zero-length packet after the last block, for bootloaders that otherwise wait for more data when it ends on a packet boundary */
+    #[arg(long)]
+    pub send_zlp: bool,
+    /** Bulk IN buffer size for device responses, default 2048. Must fit a chip-rev ack packet */
+    #[arg(long, value_name = "bytes", value_parser = parse_rx_buf)]
+    pub rx_buf: Option<usize>,
+    /** USB interface to claim for the download */
+    #[arg(long, value_name = "n", default_value_t = 0)]
+    pub interface: u8,
+    /** Select alternate setting <n> of the interface after claiming it, default is to leave it unchanged */
+    #[arg(long, value_name = "n")]
+    pub alt_setting: Option<u8>,
+    /** Extension header the chip-rev answer has to carry, default ab95. Any other answer is taken as revision A0 */
+    #[arg(long, value_name = "hex", value_parser = parse_hex_u16)]
+    pub extend_hdr: Option<u16>,
+    /** Extension version the chip-rev answer has to carry, default 1. Any other answer is taken as revision A0 */
+    #[arg(long, value_name = "hex", value_parser = parse_hex_u16)]
+    pub extend_ver: Option<u16>,
+    /** Skip the chip-rev handshake. With --fw-dir only the <chip>.bin names are tried then */
+    #[arg(long)]
+    pub no_chip_rev: bool,
+    /** Detach a kernel driver bound to the interface before claiming it */
+    #[arg(long)]
+    pub detach: bool,
+    /** USB reset the device after the download */
+    #[arg(long)]
+    pub reset: bool,
+}
+
+/** Where `flash` reads the firmware from, exactly one is required */
+#[derive(Debug, clap::Args)]
+#[group(required = true, multiple = false)]
+pub struct FlashFwArgs {
+    /** Firmware files, - for stdin, or http(s) URLs. Several are downloaded in the given order, e.g. a helper image then the main one */
+    pub fw: Vec<String>,
+    /** Pick the firmware from <dir> by the detected chip and revision */
+    #[arg(long, value_name = "dir")]
+    pub fw_dir: Option<PathBuf>,
+}
+
+/**
+ * rusb as required in Cargo.toml, keep the two in sync. The exact release isn't known at build time
+ * without a lockfile, which a library consumer's build doesn't have next to this crate
+ */
+const RUSB_REQUIREMENT: &str = "0.9";
+
+/** Crate version plus the rusb requirement and libusb runtime version, which matter for bug reports */
+fn version() -> String {
+    let libusb = rusb::version();
+    format!("{}\nrusb requirement {}\nlibusb {}.{}.{}.{}{}",
+        env!("CARGO_PKG_VERSION"),
+        RUSB_REQUIREMENT,
+        libusb.major(),
+        libusb.minor(),
+        libusb.micro(),
+        libusb.nano(),
+        libusb.rc().unwrap_or(""))
+}
+
+/** `Cli::parse`, with the runtime library versions in `--version` */
+pub fn parse() -> Cli {
+    let matches = Cli::command().version(version()).try_get_matches().unwrap_or_else(|e| usage_exit(e));
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| usage_exit(e));
+    let applied = config::load(cli.global.config.as_deref()).and_then(|config| apply_config(&mut cli, &config, &matches));
+    if let Err(message) = applied {
+        usage_exit(Cli::command().error(ErrorKind::Io, message));
+    }
+    if let Command::Flash(flash) = &cli.command {
+        let regions = flash.region_order.len();
+        if regions != 0 && regions != flash.fw.fw.len() {
+            let message = format!("--region-order names {regions} regions but {} firmware files were given", flash.fw.fw.len());
+            usage_exit(Cli::command().error(ErrorKind::WrongNumberOfValues, message));
+        }
+        if flash.dump_firmware.is_some() && flash.fw.fw.len() > 1 {
+            Cli::command().error(ErrorKind::ArgumentConflict, "--dump-firmware takes a single firmware file").exit();
+        }
+    }
+    cli
+}
+
+/** Print `e` and exit, with `EXIT_USAGE` for actual errors rather than --help or --version */
+fn usage_exit(e: clap::Error) -> ! {
+    if !e.use_stderr() {
+        e.exit();
+    }
+    let _ = e.print();
+    std::process::exit(EXIT_USAGE)
+}
+
+/** Fill in every option the command line left unset from `config` */
+fn apply_config(cli: &mut Cli, config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    // Options with a default can't tell "unset" from their value
+    let given = |matches: &ArgMatches, id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    if cli.global.timeout.is_none() {
+        cli.global.timeout = config.timeout;
+    }
+    if let Some(vendor) = config.vendor.as_deref().filter(|_| !given(matches, "vendor")) {
+        cli.global.vendor = parse_hex_u16(vendor).map_err(|e| format!("invalid vendor {vendor} in config: {e}"))?;
+    }
+
+    // Only `flash` and `probe` have an --interface to ask about
+    let interface = || {
+        let sub_matches = matches.subcommand().map(|(_, sub_matches)| sub_matches)?;
+        config.interface.filter(|_| !given(sub_matches, "interface"))
+    };
+    match &mut cli.command {
+        Command::Flash(flash) => {
+            if flash.retries.is_none() {
+                // Same lower bound as --retries
+                flash.retries = match config.retries {
+                    Some(0) => return Err("invalid retries 0 in config: at least one attempt is needed".into()),
+                    retries => retries,
+                };
+            }
+            if let Some(interface) = interface() {
+                flash.interface = interface;
+            }
+        },
+        Command::Probe(probe) => {
+            if let Some(interface) = interface() {
+                probe.interface = interface;
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+fn parse_hex_u16(value: &str) -> Result<u16, String> {
+    let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|e| e.to_string())
+}
+
+fn parse_hex_u8(value: &str) -> Result<u8, String> {
+    let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+    u8::from_str_radix(digits, 16).map_err(|e| e.to_string())
+}
+
+fn parse_rx_buf(value: &str) -> Result<usize, String> {
+    let size: usize = value.parse().map_err(|e: std::num::ParseIntError| e.to_string())?;
+    // The chip-rev ack is the larger of the two responses
+    let min = std::mem::size_of::<UsbAckPkt>().max(std::mem::size_of::<FWSyncHeader>());
+    if size < min {
+        return Err(format!("must be at least {min} bytes"));
+    }
+    Ok(size)
+}
+
+fn parse_sha256(value: &str) -> Result<[u8; 32], String> {
+    let invalid = || "expected 64 hex digits".to_string();
+    if value.len() != 64 || !value.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut digest = [0u8; 32];
+    for (byte, digits) in digest.iter_mut().zip(value.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    Ok(digest)
+}
+
+impl GlobalArgs {
+    /** Level forced by -q / -v, `None` leaves it to RUST_LOG */
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        match (self.quiet, self.verbose) {
+            (true, _) => Some(LevelFilter::Error),
+            (false, 0) => None,
+            (false, 1) => Some(LevelFilter::Info),
+            (false, _) => Some(LevelFilter::Debug),
+        }
+    }
+
+    pub fn filter(&self) -> DeviceFilter {
+        DeviceFilter { vendor: self.vendor, bus: self.bus, address: self.address, pid: self.pid }
+    }
+
+    fn download_options(&self) -> DownloadOptions {
+        let mut opts = DownloadOptions::default();
+        if let Some(timeout) = self.timeout {
+            opts.timeout = Duration::from_millis(timeout);
+        }
+        opts
+    }
+}
+
+impl FwArgs {
+    pub fn download_options(&self, global: &GlobalArgs) -> DownloadOptions {
+        DownloadOptions { sha256: self.sha256, dump_firmware: self.dump_firmware.clone(), crc_variant: self.crc_variant, strict: self.strict, check_base_addr: self.check_base_addr, ..global.download_options() }
+    }
+}
+
+impl ProbeArgs {
+    pub fn download_options(&self, global: &GlobalArgs) -> DownloadOptions {
+        DownloadOptions {
+            interface: self.interface,
+            alt_setting: self.alt_setting,
+            detach: self.detach,
+            skip_chip_rev: self.no_chip_rev,
+            strict: self.strict,
+            ..global.download_options()
+        }
+    }
+}
+
+impl FlashArgs {
+    pub fn into_args(self, global: &GlobalArgs) -> Args {
+        let mut opts = DownloadOptions {
+            sha256: self.sha256,
+            dump_firmware: self.dump_firmware,
+            crc_variant: self.crc_variant,
+            allow_crc_mismatch: self.allow_crc_mismatch,
+            interface: self.interface,
+            alt_setting: self.alt_setting,
+            rx_buf: self.rx_buf,
+            image_delay: Duration::from_millis(self.image_delay),
+            rehandshake: self.rehandshake,
+            ep_out_override: self.endpoint_out,
+            ep_in_override: self.endpoint_in,
+            send_zlp: self.send_zlp,
+            pad_out: match (self.pad, self.pad_to) {
+                (_, Some(bytes)) => OutPadding::To(bytes as usize),
+                (true, None) => OutPadding::MaxPacket,
+                (false, None) => OutPadding::None,
+            },
+            extend_hdr: self.extend_hdr,
+            extend_ver: self.extend_ver,
+            skip_chip_rev: self.no_chip_rev,
+            detach: self.detach,
+            reset: self.reset,
+            verify: self.verify.map(Duration::from_secs),
+            confirm_reboot: self.confirm_reboot.map(Duration::from_secs),
+            deadline: self.deadline.map(Duration::from_secs),
+            stall_timeout: self.stall_timeout.map(Duration::from_secs),
+            start_seq: self.start_seq,
+            pipelined: self.pipelined,
+            strict: self.strict,
+            check_base_addr: self.check_base_addr,
+            ..global.download_options()
+        };
+        // Unset ones keep the library defaults
+        if let Some(retries) = self.retries {
+            opts.retries = retries;
+        }
+        if let Some(retries) = self.chip_rev_retries {
+            opts.chip_rev_retries = retries;
+        }
+        if let Some(base) = self.retry_base_ms {
+            opts.retry_base = Duration::from_millis(base);
+        }
+        if let Some(max) = self.retry_max_ms {
+            opts.retry_max = Duration::from_millis(max);
+        }
+        if let Some(delay) = self.block_delay {
+            opts.block_delay = Duration::from_millis(delay);
+        }
+
+        Args {
+            fw_paths: self.fw.fw,
+            fw_dir: self.fw.fw_dir,
+            json: self.json,
+            sync_log: self.sync_log,
+            all: self.all,
+            jobs: self.jobs,
+            repeat: self.repeat,
+            keep_going: self.keep_going,
+            regions: self.region_order,
+            libusb_debug: global.libusb_debug,
+            filter: global.filter(),
+            wait: Duration::from_secs(self.wait),
+            wait_ready: self.wait_ready.map(Duration::from_secs),
+            opts,
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/** Name of the config file looked up in `~/.config` when `--config` isn't given */
+const CONFIG_FILE_NAME: &str = "marvellfw.toml";
+
+/**
+ * Defaults for the options operators keep passing, read from a TOML file.
+ * Anything given on the command line wins over the file, the file over the built-in defaults
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /** `--timeout`, in ms */
+    pub timeout: Option<u64>,
+    /** `--retries` of `flash` */
+    pub retries: Option<u8>,
+    /** `--interface` of `flash` and `probe` */
+    pub interface: Option<u8>,
+    /** `--vendor`, as hex like on the command line, e.g. "1286" */
+    pub vendor: Option<String>,
+}
+
+/** `~/.config/marvellfw.toml`, `None` without a home directory */
+fn default_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(Path::new(&home).join(".config").join(CONFIG_FILE_NAME))
+}
+
+/**
+ * Read the config from `path`, or from the default location if `None`.
+ * A missing default file is an empty config, a missing explicit one an error
+ */
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(format!("can't read config {}: {e}", path.display())),
+    };
+    toml::from_str(&text).map_err(|e| format!("invalid config {}: {e}", path.display()))
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+
+/** Errors returned by the firmware download path */
+#[derive(Debug)]
+pub enum FwError {
+    /** No Marvell device was found on the bus */
+    DeviceNotFound,
+    /** No Marvell device matched the requested bus/address/pid */
+    NoMatchingDevice { bus: Option<u8>, address: Option<u8>, pid: Option<u16> },
+    /** Several devices matched where one was expected, as (bus, address, pid) */
+    AmbiguousDevice { devices: Vec<(u8, u8, u16)> },
+    /** Marvell device with a product id we don't know how to handle */
+    UnhandledProductId(u16),
+    /** Device rejected block `seq`, `code` is the raw `FWSyncHeader::cmd` */
+    SyncError { seq: u32, code: u32 },
+    /** Device acked a different sequence number than the one sent */
+    SeqMismatch { got: u32, expected: u32 },
+    /** Image has more blocks than a `u32` sequence number can count */
+    SeqOverflow,
+    /** Block data doesn't match the CRC declared in its header */
+    BlockCrcMismatch { seq: u32, expected: u32, computed: u32 },
+    /** Firmware image `name` failed validation */
+    InvalidFirmware { name: String, reason: String },
+    /** None of the `tried` file names exist in the firmware directory `dir` */
+    NoFirmwareInDir { dir: std::path::PathBuf, tried: Vec<String> },
+    /** Firmware image doesn't hash to the expected SHA-256 digest */
+    DigestMismatch { expected: [u8; 32], computed: [u8; 32] },
+    /** Firmware image ended before a block carrying the last-block flag */
+    MissingLastBlock,
+    /** Firmware block declares more data than is left in the image */
+    TruncatedBlock { index: usize, needed: usize, available: usize },
+    /** Firmware block other than CMD 7 and the last one has a zero `data_length`, an error in strict mode */
+    EmptyBlock { index: usize },
+    /** Firmware block wouldn't fit the bootloader's transmit buffer in one bulk write */
+    BlockTooLarge { index: usize, size: usize, max: usize },
+    /**
+     * Retry budget ran out on block `seq`, `last_error` is what failed on the final attempt.
+     * `last_block` is set when it was the terminal block, every other block was acked by then.
+     */
+    RetriesExhausted { seq: u32, last_block: bool, last_error: Option<Box<FwError>> },
+    /** `DownloadOptions::deadline` ran out while block `seq` was still pending */
+    DeadlineExceeded { seq: u32, deadline: std::time::Duration },
+    /** No block was acked for `timeout`, `last_seq` is the last one that was (`None` if none) */
+    Stalled { last_seq: Option<u32>, timeout: std::time::Duration },
+    /** `DownloadOptions::abort` was set, e.g. by Ctrl-C */
+    Interrupted,
+    /** Every chip-rev handshake attempt failed, `last_error` is the final one */
+    ChipRevFailed { attempts: u8, last_error: Box<FwError> },
+    /** `DownloadOptions::start_seq` is past the image's last block */
+    StartSeqOutOfRange { start_seq: u32, blocks: usize },
+    /** Condition that is only a warning unless `DownloadOptions::strict` is set */
+    StrictViolation(String),
+    /** Bulk OUT transfer accepted fewer bytes than the block size */
+    ShortWrite { sent: usize, expected: usize },
+    /** Device answered with fewer bytes than the response packet needs */
+    ShortResponse { got: usize, expected: usize },
+    /**
+     * Device didn't re-enumerate in firmware-ready mode within `waited`.
+     * `state` is what it was last seen in, `None` if it never came back
+     */
+    NotReady { state: Option<crate::BootState>, waited: std::time::Duration },
+    /** Firmware-ready state was asked to be checked, but the chip's runtime pid is unknown */
+    UnknownRuntimePid(crate::MarvellChip),
+    /** No firmware-ready device answered a GET_DESCRIPTOR request within `waited` */
+    RebootNotConfirmed { waited: std::time::Duration },
+    /** Forced endpoint `address` has the wrong direction bit for its role */
+    EndpointDirection { address: u8, expected: rusb::Direction },
+    /** Active configuration has no interface with this number */
+    NoSuchInterface(u8),
+    /** `interface` has no alternate setting with this number */
+    NoSuchAltSetting { interface: u8, alt_setting: u8 },
+    /** Interface is claimed by a kernel driver (or another process) and couldn't be taken over */
+    InterfaceBusy(u8),
+    /** Device was unplugged (`NoDevice`) or stalled the endpoint (`Pipe`) mid-transfer, not retried */
+    DeviceDisconnected(rusb::Error),
+    /** Bulk OUT transfer failed */
+    UsbWrite(rusb::Error),
+    /** Bulk IN transfer failed */
+    UsbRead(rusb::Error),
+    /** Any other USB operation (open, claim, release) failed */
+    Usb(rusb::Error),
+    Io(std::io::Error),
+    Parse(binrw::Error),
+}
+
+impl FwError {
+    /** Transient transfer failures that `program_fw` retries */
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, FwError::UsbWrite(_) | FwError::UsbRead(_) | FwError::ShortWrite { .. } | FwError::ShortResponse { .. })
+    }
+}
+
+impl fmt::Display for FwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FwError::DeviceNotFound => write!(f, "No device found!"),
+            FwError::NoMatchingDevice { bus, address, pid } => {
+                write!(f, "No marvell device found")?;
+                if let Some(bus) = bus {
+                    write!(f, " on bus {bus:03}")?;
+                }
+                if let Some(address) = address {
+                    write!(f, " at address {address:03}")?;
+                }
+                if let Some(pid) = pid {
+                    write!(f, " with pid {pid:#06x}")?;
+                }
+                Ok(())
+            },
+            FwError::AmbiguousDevice { devices } => {
+                write!(f, "{} devices match, pass --bus and --address to pick one or --all to flash every one:", devices.len())?;
+                for (bus, address, pid) in devices {
+                    write!(f, "\n  Bus {bus:03} Device {address:03} pid {pid:#06x}")?;
+                }
+                Ok(())
+            },
+            FwError::UnhandledProductId(pid) => write!(f, "Unhandled marvell device with pid: {pid:#X}"),
+            FwError::SyncError { seq, code } => write!(f, "FW rejected block {seq}: {} (cmd {code:#x})", crate::decode_sync_error(*code)),
+            FwError::SeqMismatch { got, expected } => write!(f, "Mismatch in seq, got {got}, expected: {expected}"),
+            FwError::SeqOverflow => write!(f, "Firmware has too many blocks, sequence number would overflow past {}", u32::MAX),
+            FwError::BlockCrcMismatch { seq, expected, computed } => write!(f, "Firmware block {seq} is corrupt, header CRC {expected:#010x}, computed {computed:#010x}"),
+            FwError::InvalidFirmware { name, reason } => write!(f, "Invalid firmware {name}: {reason}"),
+            FwError::NoFirmwareInDir { dir, tried } => write!(f, "No firmware for this chip in {}, looked for: {}", dir.display(), tried.join(", ")),
+            FwError::DigestMismatch { expected, computed } => write!(f, "Firmware SHA-256 mismatch, expected {}, computed {}", hex(expected), hex(computed)),
+            FwError::MissingLastBlock => write!(f, "Firmware image has no last block"),
+            FwError::TruncatedBlock { index, needed, available } => write!(f, "Firmware block {index} is truncated, needs {needed} bytes but only {available} left"),
+            FwError::EmptyBlock { index } => write!(f, "Firmware block {index} has no data"),
+            FwError::BlockTooLarge { index, size, max } => write!(f, "Firmware block {index} needs a {size} byte transfer, more than the {max} byte maximum"),
+            FwError::RetriesExhausted { seq, last_block, last_error } => {
+                write!(f, "Fw download did not succeed, gave up on seq {seq}")?;
+                if *last_block {
+                    write!(f, " (last block, all earlier blocks were acked)")?;
+                }
+                if let Some(e) = last_error {
+                    write!(f, ": {e}")?;
+                }
+                Ok(())
+            },
+            FwError::DeadlineExceeded { seq, deadline } => write!(f, "Fw download exceeded its {}s deadline at seq {seq}", deadline.as_secs_f64()),
+            FwError::Stalled { last_seq: Some(seq), timeout } => write!(f, "Fw download stalled, no block acked for {}s since seq {seq}", timeout.as_secs_f64()),
+            FwError::Stalled { last_seq: None, timeout } => write!(f, "Fw download stalled, no block acked in the first {}s", timeout.as_secs_f64()),
+            FwError::Interrupted => write!(f, "Fw download interrupted"),
+            FwError::ChipRevFailed { attempts, last_error } => write!(f, "Chip-rev handshake failed after {attempts} attempts: {last_error}"),
+            FwError::StartSeqOutOfRange { start_seq, blocks } => write!(f, "Can't start at seq {start_seq}, the firmware only has {blocks} blocks"),
+            FwError::StrictViolation(condition) => write!(f, "{condition} (strict mode)"),
+            FwError::ShortWrite { sent, expected } => write!(f, "USB bulk write was short, {sent} of {expected} bytes"),
+            FwError::ShortResponse { got, expected } => write!(f, "Short response from device, {got} of {expected} bytes"),
+            FwError::NotReady { state: Some(crate::BootState::FwDownload), waited } => write!(f, "Device is still in download mode after {}s", waited.as_secs()),
+            FwError::NotReady { waited, .. } => write!(f, "Device did not come back in firmware-ready mode within {}s", waited.as_secs()),
+            FwError::UnknownRuntimePid(chip) => write!(f, "Can't confirm the firmware booted, the runtime pid of {chip:?} is unknown"),
+            FwError::RebootNotConfirmed { waited } => write!(f, "Device did not answer as the running firmware within {}s", waited.as_secs()),
+            FwError::EndpointDirection { address, expected: rusb::Direction::Out } => write!(f, "Endpoint {address:#04x} can't be the bulk OUT endpoint, bit 7 must be clear"),
+            FwError::EndpointDirection { address, expected: rusb::Direction::In } => write!(f, "Endpoint {address:#04x} can't be the bulk IN endpoint, bit 7 must be set"),
+            FwError::NoSuchInterface(iface) => write!(f, "Device has no interface {iface} in its active configuration"),
+            FwError::NoSuchAltSetting { interface, alt_setting } => write!(f, "Interface {interface} has no alternate setting {alt_setting}"),
+            FwError::InterfaceBusy(iface) => write!(f, "Interface {iface} is in use by another driver. Unload the conflicting kernel module (e.g. `modprobe -r mwifiex_usb`), retry with --detach, or run with root privileges"),
+            FwError::DeviceDisconnected(e) => write!(f, "Device disconnected or stalled during the download: {e}"),
+            FwError::UsbWrite(e) => write!(f, "USB bulk write failed: {e}"),
+            FwError::UsbRead(e) => write!(f, "USB bulk read failed: {e}"),
+            FwError::Usb(e) => write!(f, "USB error: {e}"),
+            FwError::Io(e) => write!(f, "I/O error: {e}"),
+            FwError::Parse(e) => write!(f, "Parse error: {e}"),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl std::error::Error for FwError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FwError::UsbWrite(e) | FwError::UsbRead(e) | FwError::Usb(e) | FwError::DeviceDisconnected(e) => Some(e),
+            FwError::Io(e) => Some(e),
+            FwError::Parse(e) => Some(e),
+            FwError::RetriesExhausted { last_error: Some(e), .. } => Some(e.as_ref()),
+            FwError::ChipRevFailed { last_error, .. } => Some(last_error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FwError {
+    fn from(e: std::io::Error) -> Self {
+        FwError::Io(e)
+    }
+}
+
+impl From<binrw::Error> for FwError {
+    fn from(e: binrw::Error) -> Self {
+        FwError::Parse(e)
+    }
+}
+
+impl From<rusb::Error> for FwError {
+    fn from(e: rusb::Error) -> Self {
+        FwError::Usb(e)
+    }
+}
@@ -0,0 +1,433 @@
+use std::{io::{Cursor, Read, Seek, SeekFrom}, path::{Path, PathBuf}};
+use binrw::BinRead;
+use sha2::{Digest, Sha256};
+
+use crate::{fw_data_xmit_size, ChipRev, FwError, MarvellChip, FWHeader, FW_CMD_1, FW_CMD_10, FW_CMD_7, FW_DNLD_TX_BUF_SIZE, FW_HAS_LAST_BLOCK};
+
+/** One download block of a firmware image: header plus the data following it */
+#[derive(Debug, Clone)]
+pub struct FwBlock {
+    pub header: FWHeader,
+    pub data: Vec<u8>,
+}
+
+impl FwBlock {
+    /** Whether this is the terminal block of the image, see `FW_HAS_LAST_BLOCK` */
+    pub fn is_last(&self) -> bool {
+        self.header.dnld_cmd != FW_CMD_7 && self.header.dnld_cmd & FW_HAS_LAST_BLOCK != 0
+    }
+
+    /**
+     * CMD 7 and empty blocks carry no data, so there is nothing to check their crc against.
+     * The empty CRC also differs per variant (0 for IEEE, 0xFFFFFFFF for MPEG-2).
+     */
+    pub fn has_crc(&self) -> bool {
+        self.header.dnld_cmd != FW_CMD_7 && !self.data.is_empty()
+    }
+
+    /** Check the data against the header CRC computed as `variant`, `seq` only labels the error */
+    pub fn verify_crc(&self, seq: u32, variant: CrcVariant) -> Result<(), FwError> {
+        if !self.has_crc() {
+            return Ok(());
+        }
+
+        let computed = variant.compute(&self.data);
+        if computed != self.header.crc {
+            return Err(FwError::BlockCrcMismatch { seq, expected: self.header.crc, computed });
+        }
+        Ok(())
+    }
+
+    /** CRC over the part of the block `coverage` names, computed as `variant` */
+    pub fn compute_crc(&self, variant: CrcVariant, coverage: CrcCoverage) -> u32 {
+        let header = [self.header.dnld_cmd, self.header.base_addr, self.header.data_length].map(u32::to_le_bytes).concat();
+        match coverage {
+            CrcCoverage::Data => variant.compute(&self.data),
+            CrcCoverage::Header => variant.compute(&header),
+            CrcCoverage::HeaderAndData => variant.compute(&[header, self.data.clone()].concat()),
+        }
+    }
+
+    /** Every variant and coverage whose CRC equals the header `crc`, empty if none does */
+    pub fn crc_matches(&self) -> Vec<(CrcVariant, CrcCoverage)> {
+        let mut matches = vec![];
+        for variant in [CrcVariant::Ieee, CrcVariant::Mpeg2] {
+            for coverage in [CrcCoverage::Data, CrcCoverage::Header, CrcCoverage::HeaderAndData] {
+                if self.compute_crc(variant, coverage) == self.header.crc {
+                    matches.push((variant, coverage));
+                }
+            }
+        }
+        matches
+    }
+}
+
+/**
+ * What a block CRC may be computed over. The bootloader checks `Data`, the others only serve
+ * `FwBlock::crc_matches` in telling apart images whose `crc` covers something else
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrcCoverage {
+    /** The block data */
+    Data,
+    /** `dnld_cmd`, `base_addr` and `data_length`, the header without `crc` itself */
+    Header,
+    /** Those header fields followed by the data */
+    HeaderAndData,
+}
+
+/**
+ * CRC-32 (IEEE 802.3, reflected, poly 0xEDB88320) over a firmware block's data,
+ * as carried in `FWHeader::crc`.
+ * CRC-32 of "123456789" is 0xCBF43926.
+ */
+pub fn fw_crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+/**
+ * CRC-32/MPEG-2 (not reflected, poly 0x04C11DB7, no final xor), used by some firmware generations.
+ * CRC-32/MPEG-2 of "123456789" is 0x0376E6E7.
+ */
+pub fn fw_crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            let mask = (crc >> 31).wrapping_neg();
+            crc = (crc << 1) ^ (0x04C11DB7 & mask);
+        }
+    }
+    crc
+}
+
+/** Algorithm behind `FWHeader::crc`, differs between firmware generations */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CrcVariant {
+    /** `fw_crc32` */
+    #[default]
+    Ieee,
+    /** `fw_crc32_mpeg2` */
+    Mpeg2,
+}
+
+impl CrcVariant {
+    pub fn compute(&self, data: &[u8]) -> u32 {
+        match self {
+            CrcVariant::Ieee => fw_crc32(data),
+            CrcVariant::Mpeg2 => fw_crc32_mpeg2(data),
+        }
+    }
+}
+
+impl std::str::FromStr for CrcVariant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ieee" => Ok(CrcVariant::Ieee),
+            "mpeg2" | "mpeg-2" => Ok(CrcVariant::Mpeg2),
+            _ => Err(format!("unknown CRC variant {s}, expected ieee or mpeg2")),
+        }
+    }
+}
+
+/**
+ * Walk a firmware image and split it into blocks, up to and including the last block.
+ * Block data is not CRC checked here, see `FwBlock::verify_crc`.
+ */
+pub fn parse_fw_blocks(fw: &[u8]) -> Result<Vec<FwBlock>, FwError> {
+    FwBlockReader::new(Cursor::new(fw))?.collect()
+}
+
+/**
+ * Incremental `parse_fw_blocks`: yields the blocks of the image in `reader` one at a time, up to
+ * and including the last block, holding only the current one in memory. The image runs from the
+ * reader's position to its end, the `Seek` bound is what tells a truncated block apart.
+ * Iteration stops after the first error
+ */
+pub struct FwBlockReader<R> {
+    reader: R,
+    /** Offset in `reader` the next header starts at */
+    pos: u64,
+    end: u64,
+    size: u64,
+    index: usize,
+    done: bool,
+}
+
+impl<R: Read + Seek> FwBlockReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, FwError> {
+        let pos = reader.stream_position()?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(pos))?;
+        Ok(FwBlockReader { reader, pos, end, size: end.saturating_sub(pos), index: 0, done: false })
+    }
+
+    /** Image size in bytes, headers included, as `Firmware::size` would report it */
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    fn read_block(&mut self) -> Result<FwBlock, FwError> {
+        if self.pos >= self.end {
+            return Err(FwError::MissingLastBlock);
+        }
+
+        let header = FWHeader::read(&mut self.reader)?;
+        self.pos += std::mem::size_of::<FWHeader>() as u64;
+        let mut data_len = header.data_length;
+
+        /* CMD 7 don't have data_length filed */
+        if header.dnld_cmd == FW_CMD_7 {
+            data_len = 0;
+        }
+
+        /* The whole block goes out in a single bulk write, which the bootloader buffers */
+        let xmit_size = fw_data_xmit_size(data_len) as usize;
+        if xmit_size > FW_DNLD_TX_BUF_SIZE {
+            return Err(FwError::BlockTooLarge { index: self.index, size: xmit_size, max: FW_DNLD_TX_BUF_SIZE });
+        }
+
+        let available = self.end.saturating_sub(self.pos) as usize;
+        if data_len as usize > available {
+            return Err(FwError::TruncatedBlock { index: self.index, needed: data_len as usize, available });
+        }
+        let mut data = vec![0u8; data_len as usize];
+        self.reader.read_exact(&mut data)?;
+        self.pos += data_len as u64;
+
+        Ok(FwBlock { header, data })
+    }
+}
+
+impl<R: Read + Seek> Iterator for FwBlockReader<R> {
+    type Item = Result<FwBlock, FwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let block = self.read_block();
+        self.index += 1;
+        // Nothing after the last block belongs to the image
+        self.done = block.as_ref().map_or(true, FwBlock::is_last);
+        Some(block)
+    }
+}
+
+/** Validated firmware image, split into its download blocks */
+#[derive(Debug, Clone)]
+pub struct Firmware {
+    name: String,
+    size: usize,
+    blocks: Vec<FwBlock>,
+}
+
+impl Firmware {
+    /** Validate and split `fw`, `name` only labels errors and logs, usually the file path */
+    pub fn parse(name: &str, fw: &[u8]) -> Result<Self, FwError> {
+        validate_fw(name, fw)?;
+        let blocks = parse_fw_blocks(fw)?;
+        Ok(Firmware { name: name.to_string(), size: fw.len(), blocks })
+    }
+
+    pub fn from_bytes(fw: &[u8]) -> Result<Self, FwError> {
+        Self::parse("<memory>", fw)
+    }
+
+    /** Load through `read_fw`, so stdin, URLs and gzip work too */
+    pub fn from_path(path: &str) -> Result<Self, FwError> {
+        Self::parse(path, &crate::read_fw(path)?)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /** Image size in bytes, headers included */
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn blocks(&self) -> &[FwBlock] {
+        &self.blocks
+    }
+
+    /** Data bytes of all blocks, headers excluded */
+    pub fn total_data_len(&self) -> usize {
+        self.blocks.iter().map(|block| block.data.len()).sum()
+    }
+
+    /** Index of the block carrying the last-block flag, parsing stops there */
+    pub fn last_block_index(&self) -> usize {
+        self.blocks.len() - 1
+    }
+
+    /**
+     * Indexes of blocks that carry no data although they should, almost certainly a corrupt image.
+     * CMD 7 never has data and the last block usually doesn't, neither counts
+     */
+    pub fn empty_blocks(&self) -> Vec<usize> {
+        self.blocks.iter().enumerate()
+            .filter(|(_, block)| block.header.data_length == 0 && block.has_crc() && !block.is_last())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /**
+     * Heuristic check of the `base_addr` progression, a parse that lost sync with the block
+     * boundaries reads wild addresses. Returns every data block that overlaps the previous data
+     * block or lands more than `BASE_ADDR_MAX_JUMP` away from its end, with what looked wrong.
+     * Real images may do either legitimately, findings are hints rather than proof of corruption
+     */
+    pub fn base_addr_anomalies(&self) -> Vec<(usize, String)> {
+        let mut anomalies = vec![];
+        let mut prev: Option<(usize, u64, u64)> = None;
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            if !block.has_crc() || block.data.is_empty() {
+                continue;
+            }
+            let start = block.header.base_addr as u64;
+            let end = start + block.data.len() as u64;
+
+            if let Some((prev_index, prev_start, prev_end)) = prev {
+                if start < prev_end && end > prev_start {
+                    anomalies.push((index, format!("{start:#010x}..{end:#010x} overlaps block {prev_index} at {prev_start:#010x}..{prev_end:#010x}")));
+                } else if start.abs_diff(prev_end) > BASE_ADDR_MAX_JUMP {
+                    anomalies.push((index, format!("base_addr {start:#010x} is {:#x} bytes away from the end of block {prev_index} at {prev_end:#010x}", start.abs_diff(prev_end))));
+                }
+            }
+            prev = Some((index, start, end));
+        }
+
+        anomalies
+    }
+
+    /** Whether every block's data matches its header CRC computed as `variant` */
+    pub fn crc_ok(&self, variant: CrcVariant) -> bool {
+        (0u32..).zip(self.blocks.iter()).all(|(seq, block)| block.verify_crc(seq, variant).is_ok())
+    }
+}
+
+/** Distance between consecutive data blocks that `Firmware::base_addr_anomalies` calls a suspicious jump */
+pub(crate) const BASE_ADDR_MAX_JUMP: u64 = 0x0100_0000;
+
+/** Download commands a firmware image may start with */
+const KNOWN_DNLD_CMDS: [u32; 4] = [FW_CMD_1, FW_HAS_LAST_BLOCK, FW_CMD_7, FW_CMD_10];
+
+/**
+ * Reject images that can't be a firmware before any USB interaction.
+ * `name` is only used to label the error, usually the file path.
+ */
+pub fn validate_fw(name: &str, fw: &[u8]) -> Result<(), FwError> {
+    let invalid = |reason: String| FwError::InvalidFirmware { name: name.to_string(), reason };
+
+    let header_size = std::mem::size_of::<FWHeader>();
+    if fw.len() < header_size {
+        return Err(invalid(format!("file is {} bytes, smaller than a {header_size} byte block header", fw.len())));
+    }
+
+    let header = FWHeader::read(&mut Cursor::new(fw))?;
+    if !KNOWN_DNLD_CMDS.contains(&header.dnld_cmd) {
+        return Err(invalid(format!("first block has unrecognized dnld_cmd {:#010x}", header.dnld_cmd)));
+    }
+
+    parse_fw_blocks(fw).map_err(|e| invalid(e.to_string()))?;
+
+    Ok(())
+}
+
+/**
+ * File names `find_fw_in_dir` tries for `chip`, most specific first:
+ * `<chip>_<rev>.bin` (e.g. `88w8897_b0.bin`) if the revision is known, then `<chip>.bin`.
+ * Each also as a gzip compressed `.bin.gz`.
+ */
+pub fn fw_file_names(chip: MarvellChip, rev: Option<ChipRev>) -> Vec<String> {
+    let mut stems = vec![];
+    if let Some(rev) = rev {
+        stems.push(format!("{}_{}", chip.fw_name(), rev.fw_name()));
+    }
+    stems.push(chip.fw_name());
+
+    stems.iter()
+        .flat_map(|stem| [format!("{stem}.bin"), format!("{stem}.bin.gz")])
+        .collect()
+}
+
+/** First of the `fw_file_names` present in `dir` */
+pub fn find_fw_in_dir(dir: &Path, chip: MarvellChip, rev: Option<ChipRev>) -> Result<PathBuf, FwError> {
+    let tried = fw_file_names(chip, rev);
+    match tried.iter().map(|name| dir.join(name)).find(|path| path.is_file()) {
+        Some(path) => Ok(path),
+        None => Err(FwError::NoFirmwareInDir { dir: dir.to_path_buf(), tried }),
+    }
+}
+
+/** SHA-256 of a firmware image */
+pub fn fw_sha256(fw: &[u8]) -> [u8; 32] {
+    Sha256::digest(fw).into()
+}
+
+/** Compare `fw` against an expected SHA-256 digest, the error carries both */
+pub fn verify_sha256(fw: &[u8], expected: &[u8; 32]) -> Result<(), FwError> {
+    let computed = fw_sha256(fw);
+    if computed != *expected {
+        return Err(FwError::DigestMismatch { expected: *expected, computed });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::CRC_CHECK_INPUT;
+
+    /** Block bytes as found in an image, with the IEEE CRC of `data` */
+    fn raw_block(dnld_cmd: u32, data: &[u8]) -> Vec<u8> {
+        let header = [dnld_cmd, 0x1000, data.len() as u32, fw_crc32(data)];
+        [header.map(u32::to_le_bytes).concat(), data.to_vec()].concat()
+    }
+
+    fn block(dnld_cmd: u32) -> FwBlock {
+        FwBlock { header: FWHeader { dnld_cmd, base_addr: 0, data_length: 0, crc: 0 }, data: vec![] }
+    }
+
+    #[test]
+    fn is_last_checks_the_flag_bit() {
+        assert!(block(FW_HAS_LAST_BLOCK).is_last());
+        assert!(block(FW_HAS_LAST_BLOCK | FW_CMD_1).is_last());
+        assert!(block(0x8000_0000 | FW_HAS_LAST_BLOCK).is_last());
+        assert!(!block(FW_CMD_1).is_last());
+        // CMD 7 has the bit set too, but is a command of its own
+        assert!(!block(FW_CMD_7).is_last());
+    }
+
+    #[test]
+    fn parser_stops_at_flagged_block() {
+        let image = [raw_block(FW_CMD_1, b"abc"), raw_block(FW_CMD_7, b""), raw_block(0x0100 | FW_HAS_LAST_BLOCK, b"de"), raw_block(FW_CMD_1, b"trailing")].concat();
+        let blocks = parse_fw_blocks(&image).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].data, b"de");
+    }
+
+    #[test]
+    fn crc_variants_check_values() {
+        assert_eq!(CrcVariant::Ieee.compute(CRC_CHECK_INPUT), 0xcbf43926);
+        assert_eq!(CrcVariant::Mpeg2.compute(CRC_CHECK_INPUT), 0x0376e6e7);
+        assert_eq!("MPEG-2".parse::<CrcVariant>(), Ok(CrcVariant::Mpeg2));
+        assert!("crc16".parse::<CrcVariant>().is_err());
+        // Empty blocks aren't checked, whatever the variant
+        assert!(block(FW_CMD_1).verify_crc(0, CrcVariant::Mpeg2).is_ok());
+    }
+}
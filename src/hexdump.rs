@@ -0,0 +1,38 @@
+/**
+ * `xxd` style dump: offset, 16 bytes in 2-byte groups, printable ASCII.
+ * Offsets start at `base_offset`, e.g. the position of `bytes` in a larger image
+ */
+pub fn hexdump(bytes: &[u8], base_offset: usize) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        out += &format!("{:08x}:", base_offset + line * 16);
+        for i in 0..16 {
+            if i % 2 == 0 {
+                out.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => out += &format!("{byte:02x}"),
+                None => out += "  ",
+            }
+        }
+        out += "  ";
+        out.extend(chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_known_buffer() {
+        let bytes: Vec<u8> = (0x3e..0x52).collect();
+        let expected = "00000100: 3e3f 4041 4243 4445 4647 4849 4a4b 4c4d  >?@ABCDEFGHIJKLM\n\
+                        00000110: 4e4f 5051                                NOPQ\n";
+        assert_eq!(hexdump(&bytes, 0x100), expected);
+        assert_eq!(hexdump(&[0x00, 0x7f, b' '], 0), "00000000: 007f 20                                  .. \n");
+        assert_eq!(hexdump(&[], 0), "");
+    }
+}
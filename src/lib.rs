@@ -0,0 +1,1828 @@
+//! Firmware downloader for Marvell Avastar 88W8897 / 88W8782U USB adapters.
+//!
+//! Public API:
+//! - [`download_fw`]: open a device, run the chip-rev handshake and push a firmware file
+//! - [`probe_device`]: the same up to the handshake, without sending firmware, returning a [`ProbeReport`]
+//! - [`program_fw`]: push an in-memory firmware image over a claimed [`BulkTransport`],
+//!   [`program_fw_with_progress`] additionally reports [`ProgressEvent`]s, [`program_fw_async`] pipelines the blocks
+//!   and [`program_fw_streaming`] reads them from a `Read + Seek` instead of memory
+//! - [`decode_sync_error`]: best guess at the error code the bootloader acks a rejected block with
+//! - [`check_chip_rev`]: chip revision handshake, returning a [`ChipRev`]. [`decode_chip_rev`] names raw ids
+//! - [`read_fw`]: load a firmware file (or stdin, [`STDIN_PATH`], or an HTTP URL) into memory, gunzipping it if needed.
+//!   [`verify_sha256`] checks it against a known digest, [`read_fw_checked`] does both and can dump the bytes
+//! - [`Firmware`]: a validated image split into its blocks, [`load_fw`] loads one from any source
+//! - [`parse_fw_blocks`] / [`FwBlock`]: split a firmware image into download blocks, no USB needed.
+//!   [`FwBlockReader`] does the same incrementally from a reader
+//! - [`validate_fw`]: sanity check an image before touching USB
+//! - [`FwSource`] / [`find_fw_in_dir`]: firmware file, or a directory to pick it from by chip and revision
+//! - [`fw_data_xmit_size`]: bulk OUT size of a block, header included
+//! - [`hexdump`]: `xxd` style formatting of raw bytes, as used by the debug logging
+//! - [`selftest`]: check the parser, CRCs and hexdump against an embedded sample image
+//! - [`fw_crc32`]: CRC used by the firmware block headers, [`CrcVariant`] selects others like [`fw_crc32_mpeg2`], [`FwBlock::crc_matches`] finds which one an image uses
+//! - [`read_boot_state`] / [`wait_boot_state`] / [`BootState`]: check whether the device is in download mode,
+//!   confirm the device re-enumerated with the firmware running, [`confirm_reboot`] additionally queries it
+//! - [`MarvellChip`], its [`ChipQuirks`], and the wire structs [`FWHeader`], [`FWData`], [`FWSyncHeader`], [`UsbAckPkt`]
+//!   ([`SyncWinner`] decodes its winner flag)
+//! - [`DownloadReport`]: what [`download_fw`] did, [`download_fw_with_report`] keeps it on failure too, [`DownloadStats`]: what [`program_fw`] sent
+//! - [`DownloadOptions`]: timeouts and other tunables shared by the functions above, [`OutPadding`] among them
+//! - [`MARVELL_VENDOR_ID`]
+//! - [`FwError`], returned by the download functions
+//!
+//! Protocol constants and helpers mirrored from the reference driver are `pub(crate)`,
+//! they are implementation details of the download loop.
+
+#![allow(dead_code)]
+
+/** Enter a `tracing` span for the rest of the scope, compiled out without the `tracing` feature */
+macro_rules! span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($($args)*).entered();
+    };
+}
+
+mod error;
+mod firmware;
+mod hexdump;
+mod pipeline;
+mod selftest;
+mod transport;
+
+pub use error::FwError;
+pub use firmware::{find_fw_in_dir, fw_crc32, fw_crc32_mpeg2, fw_file_names, fw_sha256, parse_fw_blocks, validate_fw, verify_sha256, CrcCoverage, CrcVariant, Firmware, FwBlock, FwBlockReader};
+pub use hexdump::hexdump;
+pub use pipeline::program_fw_async;
+pub use selftest::selftest;
+pub use transport::BulkTransport;
+
+use std::{borrow::{Borrow, Cow}, io::{Cursor, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use binrw::{
+    binrw,
+    BinRead,
+    BinWrite,
+};
+use std::thread::sleep;
+use log::{debug, info, warn};
+
+/** USB vendor id of Marvell devices, the default one to scan for */
+pub const MARVELL_VENDOR_ID: u16 = 0x1286;
+
+pub(crate) const DRIVER_USB_BULK_MSG_TIMEOUT: Duration = Duration::from_millis(100);
+
+/** Bulk endpoints used when the interface descriptor doesn't tell */
+pub(crate) const DEFAULT_EP_OUT: u8 = 0x01;
+pub(crate) const DEFAULT_EP_IN: u8 = 0x81;
+/** Bulk max packet size of a high-speed endpoint, assumed when the descriptor can't be read */
+pub(crate) const DEFAULT_MAX_PACKET: u16 = 512;
+
+/** Boot state: FW download */
+pub(crate) const MARVELL_USB_FW_DNLD: u8 = 1;
+/** Boot state: FW ready */
+pub(crate) const MARVELL_USB_FW_READY: u8 = 2;
+
+/** CMD id for CMD1, plain data block */
+pub(crate) const FW_CMD_1: u32 = 0x00000001;
+/** CMD id for CMD7 */
+pub(crate) const FW_CMD_7: u32 = 0x00000007;
+/** CMD id for CMD10 */
+pub(crate) const FW_CMD_10: u32 = 0x0000000a;
+
+/**
+ * High watermark for Tx data.
+ * The reference driver stops queueing bulk OUT URBs once this many are outstanding, see `TxWindow`
+ */
+pub(crate) const MVUSB_TX_HIGH_WMARK: u8 = 6;
+
+/**
+ * Blocks sent to the device but not acked yet, never more than `MVUSB_TX_HIGH_WMARK`.
+ * The lockstep `program_fw` only ever has one out. A pipelined sender has to get a slot
+ * from `try_acquire` before every write and `release` it once the block is acked or failed
+ */
+#[derive(Debug, Default)]
+pub(crate) struct TxWindow {
+    in_flight: u8,
+}
+
+impl TxWindow {
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        if self.in_flight >= MVUSB_TX_HIGH_WMARK {
+            return false;
+        }
+        self.in_flight += 1;
+        true
+    }
+
+    pub(crate) fn release(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+}
+
+/** Number of Rx data URB */
+pub(crate) const MVUSB_RX_DATA_URB: u8 = 6;
+
+/* Transmit buffer size for chip revision check */
+pub(crate) const CHIP_REV_TX_BUF_SIZE: usize = 16;
+/* Receive buffer size for chip revision check */
+pub(crate) const CHIP_REV_RX_BUF_SIZE: usize = 2048;
+
+/* Extensions */
+pub(crate) const EXTEND_HDR: u32 = 0xAB95;
+pub(crate) const EXTEND_V1: u32 = 0x0001;
+
+/** USB8797 chip revision ID */
+pub(crate) const USB8797_A0: u32 = 0x00000000;
+pub(crate) const USB8797_B0: u32 = 0x03800010;
+
+/** Tx buffer size for firmware download*/
+pub(crate) const FW_DNLD_TX_BUF_SIZE: usize = 620;
+/** Rx buffer size for firmware download*/
+pub(crate) const FW_DNLD_RX_BUF_SIZE: usize = 2048;
+
+/** `DownloadOptions::rx_buf` above this gets a warning, responses are a few dozen bytes at most */
+pub(crate) const RX_BUF_WARN_SIZE: usize = 64 * 1024;
+/** Max firmware retry */
+pub(crate) const MAX_FW_RETRY: u8 = 3;
+
+/*
+ * Nonzero FWSyncHeader::cmd values, the bootloader rejected the block.
+ * Unconfirmed: the reference driver reports any nonzero value as a CRC error and no
+ * documentation of the codes is public, these are the meanings we guess at.
+ */
+pub(crate) const FW_SYNC_ERR_CRC: u32 = 0x00000001;
+pub(crate) const FW_SYNC_ERR_SEQ: u32 = 0x00000002;
+pub(crate) const FW_SYNC_ERR_LEN: u32 = 0x00000003;
+
+/**
+ * Firmware has last block.
+ * A flag OR'd into `FWHeader::dnld_cmd`, the command word may carry other bits next to it.
+ * CMD7 (0x7) overlaps the bit but is its own command, never a last block.
+ */
+pub(crate) const FW_HAS_LAST_BLOCK: u32 = 0x00000004;
+
+/** Bulk OUT size of a block with `data_len` bytes of data: its `FWData` header followed by the data */
+pub fn fw_data_xmit_size(data_len: u32) -> u32 {
+    std::mem::size_of::<FWHeader>() as u32 + data_len + std::mem::size_of::<u32>() as u32
+}
+
+/** FWHeader */
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct FWHeader {
+    /** FW download command */
+	pub dnld_cmd: u32,
+    /** FW base address */
+	pub base_addr: u32,
+    /** FW data length */
+	pub data_length: u32,
+    /** FW CRC */
+	pub crc: u32,
+}
+
+/** FWData */
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+pub struct FWData {
+    /** FW data header */
+	pub fw_header: FWHeader,
+    /** FW data sequence number */
+	pub seq_num: u32,
+    /* FW data buffer */
+	// data: [u8; 2],
+}
+
+/** FWSyncHeader */
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+pub struct FWSyncHeader {
+    /** FW sync header command */
+	pub cmd: u32,
+    /** FW sync header sequence number */
+	pub seq_num: u32,
+}
+
+/** Answer to the zeroed header `check_chip_rev` sends */
+#[binrw]
+#[brw(little)]
+#[derive(Debug)]
+pub struct UsbAckPkt {
+    /** Bit 31 set when the host lost the download sync, see `UsbAckPkt::winner` */
+    pub ack_winner: u32,
+    /** Echo of the header's seq, `ChipQuirks::ack_seq` for the zeroed one `check_chip_rev` sends */
+    pub seq: u32,
+    pub extend: u32,
+    pub chip_rev: u32,
+}
+
+/** `UsbAckPkt::ack_winner` bit flagging that the host did not win the sync */
+pub(crate) const ACK_NOT_WINNER: u32 = 0x80000000;
+
+/**
+ * Which side won the download sync.
+ * On combo chips several host interfaces race to download the firmware, only the
+ * winner should push it. The reference driver gives up on the download when it loses.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncWinner {
+    Host,
+    Other,
+}
+
+impl UsbAckPkt {
+    pub fn winner(&self) -> SyncWinner {
+        match self.ack_winner & ACK_NOT_WINNER {
+            0 => SyncWinner::Host,
+            _ => SyncWinner::Other,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug)]
+pub(crate) enum DriveUsbEp {
+    Ctrl = 0,
+    CmdEvent = 1,
+    Data = 2,
+}
+
+#[warn(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarvellChip {
+    Avastar88W8782U,
+    Avastar88W8897,
+    /**
+     * Marvell device outside the known pid table, forced by the user.
+     * Goes through the same `check_chip_rev` / `program_fw` path as the 88W8897.
+     */
+    Generic(u16),
+}
+
+/**
+ * Where a chip's bootloader deviates from the common download path.
+ * `download_fw` consults these instead of matching on the chip itself
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipQuirks {
+    /** Bootloader answers the zeroed header of `check_chip_rev` before the first block */
+    pub chip_rev_handshake: bool,
+    /** `UsbAckPkt::extend` value marking the handshake answer as carrying a chip revision */
+    pub ack_extend: u32,
+    /**
+     * `UsbAckPkt::seq` of a synced handshake answer. The bootloader echoes the sequence number of
+     * the header it answers, so anything else means a stale response from an earlier exchange
+     */
+    pub ack_seq: u32,
+    /**
+     * `dnld_cmd` the chip's firmware images start with, `None` if not known. A different one
+     * usually means an image for another chip, see `DownloadOptions::strict`
+     */
+    pub first_dnld_cmd: Option<u32>,
+}
+
+impl MarvellChip {
+    /** Map a download-mode USB product id to the chip */
+    pub fn from_product_id(pid: u16) -> Option<Self> {
+        match pid {
+            0x2040 => Some(MarvellChip::Avastar88W8782U),
+            0x2045 => Some(MarvellChip::Avastar88W8897),
+            _ => None,
+        }
+    }
+
+    /** Chip that enumerates with `pid` once its firmware booted, the reverse of `runtime_product_id` */
+    pub fn from_runtime_product_id(pid: u16) -> Option<Self> {
+        [MarvellChip::Avastar88W8782U, MarvellChip::Avastar88W8897].into_iter()
+            .find(|chip| chip.runtime_product_id() == Some(pid))
+    }
+
+    /**
+     * Product id the chip re-enumerates with once the firmware booted ("FW ready" state).
+     * This differs from the download-mode pid the bootloader shows up with, `None` if unknown.
+     */
+    pub fn runtime_product_id(&self) -> Option<u16> {
+        match self {
+            MarvellChip::Avastar88W8897 => Some(0x2046),
+            MarvellChip::Avastar88W8782U | MarvellChip::Generic(_) => None,
+        }
+    }
+
+    /** Product id the bootloader enumerates with, waiting for a download */
+    pub fn download_product_id(&self) -> u16 {
+        match self {
+            MarvellChip::Avastar88W8782U => 0x2040,
+            MarvellChip::Avastar88W8897 => 0x2045,
+            MarvellChip::Generic(pid) => *pid,
+        }
+    }
+
+    /** Chip part of the `fw_file_names` convention */
+    pub fn fw_name(&self) -> String {
+        match self {
+            MarvellChip::Avastar88W8782U => "88w8782u".into(),
+            MarvellChip::Avastar88W8897 => "88w8897".into(),
+            MarvellChip::Generic(pid) => format!("{pid:04x}"),
+        }
+    }
+
+    /**
+     * Per-chip protocol differences.
+     * The 88W8897 (and unknown chips, treated like it) expect the chip-rev handshake.
+     * The 88W8782U bootloader doesn't, it takes the first firmware block right away.
+     * Where there is a handshake its header is all zeroes, so the ack echoes seq 0.
+     * 88W8897 images open with a CMD 7 block, unknown chips aren't checked for any.
+     */
+    pub fn quirks(&self) -> ChipQuirks {
+        let ack_extend = (EXTEND_HDR << 16) | EXTEND_V1;
+        match self {
+            MarvellChip::Avastar88W8782U => ChipQuirks { chip_rev_handshake: false, ack_extend, ack_seq: 0, first_dnld_cmd: None },
+            MarvellChip::Avastar88W8897 => ChipQuirks { chip_rev_handshake: true, ack_extend, ack_seq: 0, first_dnld_cmd: Some(FW_CMD_7) },
+            MarvellChip::Generic(_) => ChipQuirks { chip_rev_handshake: true, ack_extend, ack_seq: 0, first_dnld_cmd: None },
+        }
+    }
+
+    /**
+     * Boot state of a device of this chip enumerated with `pid`, as the reference driver derives it.
+     * `None` for pids that are neither, which with no known runtime pid includes every other pid:
+     * another adapter on the bus may show up under it just as well.
+     */
+    pub fn boot_state(&self, pid: u16) -> Option<BootState> {
+        if pid == self.download_product_id() {
+            return Some(BootState::FwDownload);
+        }
+        match self.runtime_product_id() {
+            Some(runtime_pid) if runtime_pid == pid => Some(BootState::FwReady),
+            _ => None,
+        }
+    }
+}
+
+/** USB boot state of the adapter, the reference driver reads it off the product id */
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootState {
+    /** Bootloader waiting for a firmware download */
+    FwDownload = MARVELL_USB_FW_DNLD,
+    /** Firmware booted */
+    FwReady = MARVELL_USB_FW_READY,
+}
+
+/**
+ * Current boot state of a `chip` device. The bootloader has no request that reports it,
+ * like the reference driver this goes by the product id the device enumerated with.
+ * `None` if the pid is neither the chip's download nor its runtime one
+ */
+pub fn read_boot_state<T: rusb::UsbContext>(device: &rusb::Device<T>, chip: MarvellChip) -> Result<Option<BootState>, FwError> {
+    let pid = device.device_descriptor()?.product_id();
+    Ok(chip.boot_state(pid))
+}
+
+/** Send+ack cycles slower than this are logged at debug level */
+pub(crate) const SLOW_BLOCK_THRESHOLD: Duration = Duration::from_millis(20);
+
+/** Upper bounds of the per-block timing histogram buckets, the last bucket is open ended */
+const BLOCK_TIME_BUCKETS: [Duration; 4] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(20),
+    Duration::from_millis(100),
+];
+
+/** One line per bucket of `BLOCK_TIME_BUCKETS`, counting how many blocks took that long */
+fn block_time_histogram(timings: &[Duration]) -> String {
+    let mut counts = [0usize; BLOCK_TIME_BUCKETS.len() + 1];
+    for elapsed in timings {
+        let bucket = BLOCK_TIME_BUCKETS.iter().position(|bound| elapsed < bound).unwrap_or(BLOCK_TIME_BUCKETS.len());
+        counts[bucket] += 1;
+    }
+
+    let mut lower = Duration::ZERO;
+    let mut lines = vec![];
+    for (bound, count) in BLOCK_TIME_BUCKETS.iter().zip(counts) {
+        lines.push(format!("  {lower:>6.0?} - {bound:>6.0?}: {count}"));
+        lower = *bound;
+    }
+    lines.push(format!("  {lower:>6.0?} +        : {}", counts[BLOCK_TIME_BUCKETS.len()]));
+    lines.join("\n")
+}
+
+/** Default first retry delay and cap of the per-block retry backoff */
+pub(crate) const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/** Delay between bus scans while waiting for the device to re-enumerate */
+pub(crate) const REENUMERATE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/**
+ * Rescan `bus` until a `chip` device with vendor id `vendor_id` shows up in firmware-ready state
+ * or `timeout` runs out. Returns the boot state last seen, `None` if no device of the chip came back at all.
+ * Setting `abort` ends the wait with `FwError::Interrupted`
+ */
+pub fn wait_boot_state<T: rusb::UsbContext>(context: &T, vendor_id: u16, chip: MarvellChip, bus: u8, timeout: Duration, abort: Option<&AtomicBool>) -> Result<Option<BootState>, FwError> {
+    let start = Instant::now();
+
+    loop {
+        check_aborted(abort)?;
+        let mut seen = None;
+        for device in context.devices()?.iter() {
+            let device_desc = device.device_descriptor()?;
+            if device_desc.vendor_id() != vendor_id || device.bus_number() != bus {
+                continue;
+            }
+
+            match chip.boot_state(device_desc.product_id()) {
+                Some(BootState::FwReady) => {
+                    info!("Firmware ready: Bus {:03} Device {:03} ID {:04x}:{:04x}",
+                        device.bus_number(),
+                        device.address(),
+                        device_desc.vendor_id(),
+                        device_desc.product_id());
+                    return Ok(Some(BootState::FwReady));
+                },
+                Some(state) => seen = Some(state),
+                None => {},
+            }
+        }
+
+        if start.elapsed() >= timeout {
+            return Ok(seen);
+        }
+        debug!("Waiting for firmware-ready device");
+        sleep(REENUMERATE_POLL_INTERVAL);
+    }
+}
+
+/** Size of a standard USB device descriptor */
+pub(crate) const DEVICE_DESCRIPTOR_SIZE: usize = 18;
+
+/** Offset of `idProduct` in a device descriptor */
+pub(crate) const DEVICE_DESCRIPTOR_PID_OFFSET: usize = 10;
+
+/** Product id from a GET_DESCRIPTOR request to the device itself, rather than libusb's cached copy */
+fn query_product_id<T: rusb::UsbContext>(device: &rusb::Device<T>, timeout: Duration) -> Result<u16, FwError> {
+    let handle = device.open()?;
+    let mut buf = [0u8; DEVICE_DESCRIPTOR_SIZE];
+    let request_type = rusb::request_type(rusb::Direction::In, rusb::RequestType::Standard, rusb::Recipient::Device);
+    let value = (rusb::constants::LIBUSB_DT_DEVICE as u16) << 8;
+    let received = handle.read_control(request_type, rusb::constants::LIBUSB_REQUEST_GET_DESCRIPTOR, value, 0, &mut buf, timeout)?;
+
+    let expected = DEVICE_DESCRIPTOR_PID_OFFSET + 2;
+    if received < expected {
+        return Err(FwError::ShortResponse { got: received, expected });
+    }
+    Ok(u16::from_le_bytes([buf[DEVICE_DESCRIPTOR_PID_OFFSET], buf[DEVICE_DESCRIPTOR_PID_OFFSET + 1]]))
+}
+
+/**
+ * Stronger check than `wait_boot_state`: a firmware-ready `chip` device on `bus` has to answer a
+ * GET_DESCRIPTOR control transfer with its runtime product id within `timeout`.
+ * Devices that drop off the bus mid-request are still re-enumerating and get polled again.
+ * Setting `abort` ends the wait with `FwError::Interrupted`
+ */
+pub fn confirm_reboot<T: rusb::UsbContext>(context: &T, vendor_id: u16, chip: MarvellChip, bus: u8, timeout: Duration, abort: Option<&AtomicBool>) -> Result<(), FwError> {
+    let start = Instant::now();
+
+    loop {
+        check_aborted(abort)?;
+        for device in context.devices()?.iter() {
+            let Ok(device_desc) = device.device_descriptor() else {
+                continue;
+            };
+            if device_desc.vendor_id() != vendor_id || device.bus_number() != bus || chip.boot_state(device_desc.product_id()) != Some(BootState::FwReady) {
+                continue;
+            }
+
+            match query_product_id(&device, DRIVER_USB_BULK_MSG_TIMEOUT) {
+                Ok(pid) if chip.boot_state(pid) == Some(BootState::FwReady) => {
+                    info!("Reboot confirmed: Bus {:03} Device {:03} answers as pid {pid:#06x}", device.bus_number(), device.address());
+                    return Ok(());
+                },
+                Ok(pid) => debug!("Device answered GET_DESCRIPTOR with pid {pid:#06x}, not running the firmware yet"),
+                // Still reconnecting
+                Err(FwError::Usb(e @ (rusb::Error::NoDevice | rusb::Error::NotFound | rusb::Error::Io | rusb::Error::Pipe | rusb::Error::Timeout))) => {
+                    debug!("GET_DESCRIPTOR failed while the device reconnects: {e}");
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(FwError::RebootNotConfirmed { waited: timeout });
+        }
+        sleep(REENUMERATE_POLL_INTERVAL);
+    }
+}
+
+/** Tunables for the download path, `Default` matches the reference driver */
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /**
+     * Timeout for every bulk write and read.
+     * Raising it slows down failure detection but helps on flaky hubs or passthrough setups.
+     */
+    pub timeout: Duration,
+    /** Attempts per firmware block before giving up */
+    pub retries: u8,
+    /** Wall-clock cap on a whole `program_fw` call, `None` lets it run as long as blocks get acked */
+    pub deadline: Option<Duration>,
+    /**
+     * Abort `program_fw` with `FwError::Stalled` once this long passed since the last acked block
+     * (or the start), e.g. when writes keep going through but the sequence never advances. `None` is off.
+     * `program_fw_async` doesn't apply it
+     */
+    pub stall_timeout: Option<Duration>,
+    /**
+     * Sequence number to start `program_fw` at, earlier blocks are skipped. Zero by default.
+     * A protocol debugging aid, the bootloader generally expects the full sequence from block 0
+     * and resuming a failed download this way is not guaranteed to work
+     */
+    pub start_seq: u32,
+    /**
+     * Turn suspicious but survivable conditions from warnings into errors:
+     * a firmware block without data (`FwError::EmptyBlock`), a device already running its
+     * firmware, a chip-rev response with an unknown revision, an unexpected seq or without the
+     * extended ack, the host losing the ack-winner race, bulk endpoints that couldn't be detected,
+     * a last block that went out but was never acked, an image not starting with the chip's
+     * `ChipQuirks::first_dnld_cmd`, and with `check_base_addr` its findings
+     * (all `FwError::StrictViolation`). Also overrides `allow_crc_mismatch`.
+     * Rejected blocks fail the download either way, whether or not their sync code is known
+     */
+    pub strict: bool,
+    /**
+     * Have `load_fw` warn about overlapping or far apart `base_addr`s, see
+     * `Firmware::base_addr_anomalies`. A heuristic, strict mode makes the findings errors
+     */
+    pub check_base_addr: bool,
+    /** `download_fw` sends the blocks with the experimental `program_fw_async` instead of `program_fw` */
+    pub pipelined: bool,
+    /** Attempts at the chip-rev handshake before giving up */
+    pub chip_rev_retries: u8,
+    /** Delay after the first failed attempt at a block, doubled on every further consecutive failure */
+    pub retry_base: Duration,
+    /** Upper bound of the retry delay */
+    pub retry_max: Duration,
+    /**
+     * Pause after every acked block, zero by default.
+     * Trades throughput for stability on links that drop transfers when driven back to back
+     */
+    pub block_delay: Duration,
+    /**
+     * Expected SHA-256 of the image as `read_fw` returns it, checked before flashing.
+     * File images are checked before the device gets opened, `FwSource::Dir` ones only once picked
+     */
+    pub sha256: Option<[u8; 32]>,
+    /**
+     * File `load_fw` writes the image to as read, after fetching and gunzipping: exactly the bytes
+     * that get parsed and flashed. Overwritten by every image loaded
+     */
+    pub dump_firmware: Option<PathBuf>,
+    /** Algorithm the block header CRCs are checked with */
+    pub crc_variant: CrcVariant,
+    /**
+     * Send blocks whose data fails the header CRC anyway, warning instead of failing with
+     * `FwError::BlockCrcMismatch`. For images whose CRC doesn't cover what we expect, ignored in strict mode
+     */
+    pub allow_crc_mismatch: bool,
+    /** Interface `download_fw` claims, the bootloader's bulk endpoints live on it */
+    pub interface: u8,
+    /** Alternate setting `download_fw` selects on `interface` after claiming it, `None` leaves it unchanged */
+    pub alt_setting: Option<u8>,
+    /**
+     * Upper half of the `UsbAckPkt::extend` value `check_chip_rev` expects instead of `EXTEND_HDR`.
+     * An answer with another value is taken as USB8797 A0, as without the override
+     */
+    pub extend_hdr: Option<u16>,
+    /** Lower half of the expected `UsbAckPkt::extend` value instead of `EXTEND_V1`, see `extend_hdr` */
+    pub extend_ver: Option<u16>,
+    /** Skip the chip-rev handshake even on chips that expect it, the report has no revision then */
+    pub skip_chip_rev: bool,
+    /**
+     * Explicitly detach a kernel driver bound to `interface` before claiming it,
+     * instead of relying on libusb's auto-detach
+     */
+    pub detach: bool,
+    /** USB reset the device after the download to force re-enumeration, off by default */
+    pub reset: bool,
+    /**
+     * After the download, wait up to this long for the device to come back in firmware-ready
+     * state and fail otherwise, also when the chip's runtime pid is unknown. `None` skips the check
+     */
+    pub verify: Option<Duration>,
+    /** After the download, wait up to this long for the firmware to answer a control transfer, see `confirm_reboot` */
+    pub confirm_reboot: Option<Duration>,
+    /**
+     * Bulk IN buffer for sync responses and the chip-rev ack instead of the reference driver's
+     * 2048 bytes. Too small for a `UsbAckPkt` makes every response short, a protocol debugging knob
+     */
+    pub rx_buf: Option<usize>,
+    /**
+     * Set from elsewhere (e.g. a Ctrl-C handler) to stop the download before its next block or
+     * attempt, or the `verify` / `confirm_reboot` wait, with `FwError::Interrupted`.
+     * `download_fw` still releases the interface then
+     */
+    pub abort: Option<Arc<AtomicBool>>,
+    /**
+     * CSV file every received `FWSyncHeader` gets appended to as `timestamp_ms,seq,cmd`, the
+     * timestamp in milliseconds since the Unix epoch. Written unbuffered, one line per response
+     */
+    pub sync_log: Option<Arc<Mutex<std::fs::File>>>,
+    /** Pause between the images of a `FwSource::Sequence`, zero by default */
+    pub image_delay: Duration,
+    /** Repeat the chip-rev handshake before every image of a `FwSource::Sequence` after the first */
+    pub rehandshake: bool,
+    /** Bulk OUT endpoint address, `download_fw` replaces it with the detected one */
+    pub ep_out: u8,
+    /** Bulk IN endpoint address, `download_fw` replaces it with the detected one */
+    pub ep_in: u8,
+    /** Bulk OUT endpoint `download_fw` uses regardless of what it detects, must have bit 7 clear */
+    pub ep_out_override: Option<u8>,
+    /** Bulk IN endpoint `download_fw` uses regardless of what it detects, must have bit 7 set */
+    pub ep_in_override: Option<u8>,
+    /**
+     * Zero padding of every bulk OUT buffer, `OutPadding::None` by default since bootloaders
+     * that check the exact transfer length reject padded blocks
+     */
+    pub pad_out: OutPadding,
+    /** `wMaxPacketSize` of `ep_out` for `OutPadding::MaxPacket`, `download_fw` replaces it with the detected one */
+    pub ep_out_max_packet: u16,
+    /**
+     * Follow the last block with a zero-length bulk OUT packet before waiting for its ack. A transfer
+     * that is an exact multiple of the max packet size has no short packet to end it, so a
+     * bootloader that reads until one may sit waiting for more data. Off by default
+     */
+    pub send_zlp: bool,
+}
+
+/**
+ * How `program_fw` pads the bulk OUT buffer of a block, for bootloaders that stall on short packets.
+ * A block whose padded size exceeds the bootloader's 620 byte transmit buffer fails with `BlockTooLarge`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutPadding {
+    #[default]
+    None,
+    /** Up to the next multiple of `DownloadOptions::ep_out_max_packet` */
+    MaxPacket,
+    /** Up to the next multiple of this many bytes */
+    To(usize),
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            timeout: DRIVER_USB_BULK_MSG_TIMEOUT,
+            retries: MAX_FW_RETRY,
+            deadline: None,
+            stall_timeout: None,
+            start_seq: 0,
+            pipelined: false,
+            strict: false,
+            check_base_addr: false,
+            chip_rev_retries: MAX_FW_RETRY,
+            retry_base: RETRY_BACKOFF,
+            retry_max: RETRY_BACKOFF,
+            block_delay: Duration::ZERO,
+            sha256: None,
+            dump_firmware: None,
+            crc_variant: CrcVariant::Ieee,
+            allow_crc_mismatch: false,
+            interface: 0,
+            alt_setting: None,
+            extend_hdr: None,
+            extend_ver: None,
+            skip_chip_rev: false,
+            detach: false,
+            reset: false,
+            verify: None,
+            confirm_reboot: None,
+            rx_buf: None,
+            abort: None,
+            sync_log: None,
+            image_delay: Duration::ZERO,
+            rehandshake: false,
+            ep_out: DEFAULT_EP_OUT,
+            ep_in: DEFAULT_EP_IN,
+            ep_out_override: None,
+            ep_in_override: None,
+            pad_out: OutPadding::None,
+            ep_out_max_packet: DEFAULT_MAX_PACKET,
+            send_zlp: false,
+        }
+    }
+}
+
+/** Human readable name of a raw chip revision id */
+pub fn decode_chip_rev(raw: u32) -> Cow<'static, str> {
+    match raw {
+        USB8797_A0 => "USB8797 A0".into(),
+        USB8797_B0 => "USB8797 B0".into(),
+        _ => format!("unknown ({raw:#010x})").into(),
+    }
+}
+
+/** Silicon revision answered by the chip-rev handshake */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChipRev {
+    A0,
+    B0,
+    Unknown(u32),
+}
+
+impl ChipRev {
+    pub fn from_raw(raw: u32) -> Self {
+        match raw {
+            USB8797_A0 => ChipRev::A0,
+            USB8797_B0 => ChipRev::B0,
+            _ => ChipRev::Unknown(raw),
+        }
+    }
+
+    /** Revision id as carried in `UsbAckPkt::chip_rev` */
+    pub fn raw(&self) -> u32 {
+        match self {
+            ChipRev::A0 => USB8797_A0,
+            ChipRev::B0 => USB8797_B0,
+            ChipRev::Unknown(raw) => *raw,
+        }
+    }
+}
+
+impl ChipRev {
+    /** Revision part of the `fw_file_names` convention */
+    pub fn fw_name(&self) -> String {
+        match self {
+            ChipRev::A0 => "a0".into(),
+            ChipRev::B0 => "b0".into(),
+            ChipRev::Unknown(raw) => format!("{raw:08x}"),
+        }
+    }
+}
+
+impl std::fmt::Display for ChipRev {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&decode_chip_rev(self.raw()))
+    }
+}
+
+/**
+ * Human readable meaning of a nonzero `FWSyncHeader::cmd`.
+ * The names are unconfirmed guesses and say so, the raw code is what to go by
+ */
+pub fn decode_sync_error(code: u32) -> Cow<'static, str> {
+    match code {
+        FW_SYNC_ERR_CRC => "CRC mismatch (unconfirmed)".into(),
+        FW_SYNC_ERR_SEQ => "sequence error (unconfirmed)".into(),
+        FW_SYNC_ERR_LEN => "length error (unconfirmed)".into(),
+        _ => format!("unknown error ({code:#010x})").into(),
+    }
+}
+
+/** Path that makes `read_fw` read the image from stdin */
+pub const STDIN_PATH: &str = "-";
+
+/** Magic at the start of a gzip stream */
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/**
+ * Load a firmware image, `STDIN_PATH` reads all of stdin instead of a file and
+ * `http://` / `https://` URLs are fetched.
+ * Gzip compressed images are decompressed transparently.
+ */
+pub fn read_fw(path: &str) -> Result<Vec<u8>, std::io::Error> {
+    let buf = read_fw_raw(path)?;
+    if !buf.starts_with(&GZIP_MAGIC) {
+        return Ok(buf);
+    }
+
+    let mut decompressed = vec![];
+    flate2::read::GzDecoder::new(buf.as_slice()).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/** Whether `read_fw` fetches `path` over HTTP */
+pub fn is_fw_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+fn fetch_fw(url: &str) -> Result<Vec<u8>, std::io::Error> {
+    let response = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .map_err(std::io::Error::other)?;
+    Ok(response.bytes().map_err(std::io::Error::other)?.to_vec())
+}
+
+fn read_fw_raw(path: &str) -> Result<Vec<u8>, std::io::Error> {
+    if is_fw_url(path) {
+        return fetch_fw(path);
+    }
+
+    if path == STDIN_PATH {
+        let mut buf = vec![];
+        std::io::stdin().lock().read_to_end(&mut buf)?;
+        return Ok(buf);
+    }
+
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::End(0))?;
+    let fsize = file.stream_position()?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut buf = vec![0u8; fsize as usize];
+    file.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+/** Progress notifications from `program_fw_with_progress` */
+#[derive(Debug, Clone, Copy)]
+pub enum ProgressEvent {
+    /** Block `seq` was acked, `bytes_sent` of the `total_bytes` image are through */
+    BlockAcked { seq: u32, bytes_sent: usize, total_bytes: usize },
+    /** An attempt at block `seq` failed, `retries_left` attempts remain */
+    Retry { seq: u32, retries_left: u8, bytes_sent: usize, total_bytes: usize },
+}
+
+/** Bytes of a response or block dumped at debug level */
+pub(crate) const RESPONSE_DUMP_LEN: usize = 32;
+
+/** Bulk transfer error, with unplug and stall split off since retrying can't fix them */
+fn transfer_error(e: rusb::Error, other: fn(rusb::Error) -> FwError) -> FwError {
+    match e {
+        rusb::Error::NoDevice | rusb::Error::Pipe => FwError::DeviceDisconnected(e),
+        e => other(e),
+    }
+}
+
+/** `FWData` header of block `seq_num` followed by its data, sent as one bulk write */
+pub(crate) fn block_buffer(block: &FwBlock, seq_num: u32) -> Result<Vec<u8>, FwError> {
+    // Prepare fw block to send
+    let fw_data = FWData {
+        fw_header: block.header.clone(),
+        seq_num
+    };
+
+    // CMD 7 carries a data_length but no data, so size by what was actually read
+    let xmit_size = fw_data_xmit_size(block.data.len() as u32) as usize;
+    let mut send_buffer = Vec::with_capacity(xmit_size);
+    let mut writer = Cursor::new(&mut send_buffer);
+    // Write fw header + sequence
+    fw_data.write(&mut writer)?;
+
+    // Append data portion
+    writer.write_all(&block.data)?;
+    debug_assert_eq!(send_buffer.len(), xmit_size, "block buffer doesn't match fw_data_xmit_size");
+    Ok(send_buffer)
+}
+
+/**
+ * Zero pad a `block_buffer` as `DownloadOptions::pad_out` asks.
+ * The padded transfer still has to fit the bootloader's transmit buffer
+ */
+pub(crate) fn pad_buffer(send_buffer: &mut Vec<u8>, seq_num: u32, opts: &DownloadOptions) -> Result<(), FwError> {
+    let multiple = match opts.pad_out {
+        OutPadding::None => return Ok(()),
+        OutPadding::MaxPacket => opts.ep_out_max_packet as usize,
+        OutPadding::To(bytes) => bytes,
+    };
+    if multiple == 0 {
+        return Ok(());
+    }
+
+    let padded = send_buffer.len().next_multiple_of(multiple);
+    if padded > FW_DNLD_TX_BUF_SIZE {
+        return Err(FwError::BlockTooLarge { index: seq_num as usize, size: padded, max: FW_DNLD_TX_BUF_SIZE });
+    }
+    debug!("Block {seq_num}: padding {} bytes to {padded}", send_buffer.len());
+    send_buffer.resize(padded, 0);
+    Ok(())
+}
+
+pub(crate) fn write_block<B: BulkTransport>(handle: &B, send_buffer: &[u8], opts: &DownloadOptions) -> Result<(), FwError> {
+    span!("write_bulk", ep = opts.ep_out, bytes = send_buffer.len());
+    let sent = handle.write_bulk(opts.ep_out, send_buffer, opts.timeout).map_err(|e| transfer_error(e, FwError::UsbWrite))?;
+    if sent != send_buffer.len() {
+        return Err(FwError::ShortWrite { sent, expected: send_buffer.len() });
+    }
+    Ok(())
+}
+
+/** Zero-length packet ending the last block's transfer, if `DownloadOptions::send_zlp` asks for one */
+pub(crate) fn write_zlp<B: BulkTransport>(handle: &B, opts: &DownloadOptions) -> Result<(), FwError> {
+    if !opts.send_zlp {
+        return Ok(());
+    }
+    debug!("Sending zero-length packet");
+    handle.write_bulk(opts.ep_out, &[], opts.timeout).map_err(|e| transfer_error(e, FwError::UsbWrite))?;
+    Ok(())
+}
+
+/** Receive the sync response to a block, returned with the raw bytes for dumping */
+pub(crate) fn read_sync<B: BulkTransport>(handle: &B, opts: &DownloadOptions) -> Result<(FWSyncHeader, Vec<u8>), FwError> {
+    span!("read_bulk", ep = opts.ep_in, bytes = opts.rx_buf.unwrap_or(FW_DNLD_RX_BUF_SIZE));
+    let mut recv_buffer = vec![0u8; opts.rx_buf.unwrap_or(FW_DNLD_RX_BUF_SIZE)];
+    let received = handle.read_bulk(opts.ep_in, &mut recv_buffer, opts.timeout).map_err(|e| transfer_error(e, FwError::UsbRead))?;
+    recv_buffer.truncate(received);
+
+    let expected = std::mem::size_of::<FWSyncHeader>();
+    if received < expected {
+        return Err(FwError::ShortResponse { got: received, expected });
+    }
+    let sync_header = FWSyncHeader::read(&mut Cursor::new(&recv_buffer))?;
+    log_sync(opts, &sync_header);
+    Ok((sync_header, recv_buffer))
+}
+
+/** Append `sync_header` to `DownloadOptions::sync_log`, a failing log only warns */
+fn log_sync(opts: &DownloadOptions, sync_header: &FWSyncHeader) {
+    let Some(sync_log) = &opts.sync_log else {
+        return;
+    };
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let line = format!("{timestamp},{},{:#x}\n", sync_header.seq_num, sync_header.cmd);
+    let mut file = sync_log.lock().unwrap();
+    if let Err(e) = file.write_all(line.as_bytes()).and_then(|()| file.flush()) {
+        warn!("Couldn't write the sync log: {e}");
+    }
+}
+
+/** Sync read failures after the last block went out that mean the device left the bus to boot, not a rejection */
+pub(crate) fn is_lost_last_ack(e: &FwError) -> bool {
+    matches!(e, FwError::UsbRead(rusb::Error::Timeout) | FwError::DeviceDisconnected(rusb::Error::NoDevice))
+}
+
+/** Devices that re-enumerate quickly can boot the firmware before acking the last block, warn (or fail in strict mode) and go on */
+pub(crate) fn accept_lost_last_ack(e: &FwError, opts: &DownloadOptions) -> Result<(), FwError> {
+    soft_failure(opts, format!("No ack for the last block ({e}), assuming the device booted the firmware. Use --verify to confirm"))
+}
+
+/** Whether the device accepted block `seq_num`, unexpected responses get hex dumped */
+pub(crate) fn check_sync(sync_header: &FWSyncHeader, recv_buffer: &[u8], seq_num: u32) -> Result<(), FwError> {
+    debug!("Sync header: {sync_header:?}");
+
+    if sync_header.cmd > 0 || sync_header.seq_num != seq_num {
+        let len = recv_buffer.len().min(RESPONSE_DUMP_LEN);
+        debug!("Unexpected sync response:\n{}", hexdump(&recv_buffer[..len], 0));
+    }
+
+    if sync_header.cmd > 0 {
+        return Err(FwError::SyncError { seq: seq_num, code: sync_header.cmd });
+    }
+    else if sync_header.seq_num != seq_num {
+        return Err(FwError::SeqMismatch { got: sync_header.seq_num, expected: seq_num });
+    }
+    Ok(())
+}
+
+/** Sequence number of the block after `seq_num` */
+fn next_seq(seq_num: u32) -> Result<u32, FwError> {
+    seq_num.checked_add(1).ok_or(FwError::SeqOverflow)
+}
+
+/** What `program_fw` sent, `program_fw_into` also counts into it on failure */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DownloadStats {
+    /** Blocks acked by the device */
+    pub blocks_sent: u32,
+    /** Bulk OUT bytes of the acked blocks, `FWData` headers and any `OutPadding` included */
+    pub bytes_sent: u64,
+    /** Failed attempts that were retried, summed over all blocks */
+    pub retries_used: u32,
+}
+
+pub fn program_fw<B: BulkTransport>(handle: &B, fw: &Firmware, opts: &DownloadOptions) -> Result<DownloadStats, FwError> {
+    program_fw_with_progress(handle, fw, opts, |_| {})
+}
+
+/** `program_fw`, reporting every acked block and every retry to `progress` */
+pub fn program_fw_with_progress<B, F>(handle: &B, fw: &Firmware, opts: &DownloadOptions, progress: F) -> Result<DownloadStats, FwError>
+where
+    B: BulkTransport,
+    F: FnMut(ProgressEvent),
+{
+    span!("program_fw", blocks = fw.blocks().len(), bytes = fw.size());
+    let mut stats = DownloadStats::default();
+    program_fw_into(handle, fw, opts, progress, &mut stats).map(|()| stats)
+}
+
+/** `program_fw_with_progress`, counting into `stats` as it goes, so a failed download still says what it sent and retried */
+pub(crate) fn program_fw_into<B, F>(handle: &B, fw: &Firmware, opts: &DownloadOptions, progress: F, stats: &mut DownloadStats) -> Result<(), FwError>
+where
+    B: BulkTransport,
+    F: FnMut(ProgressEvent),
+{
+    let skip = opts.start_seq as usize;
+    let result = match fw.blocks().get(skip..) {
+        Some(blocks) if !blocks.is_empty() => {
+            // Progress counts the skipped blocks as through
+            let skipped_bytes = fw.blocks()[..skip].iter().map(|block| std::mem::size_of::<FWHeader>() + block.data.len()).sum();
+            send_blocks(handle, blocks.iter().map(Ok), fw.size(), skipped_bytes, opts, progress, stats)
+        },
+        _ => Err(FwError::StartSeqOutOfRange { start_seq: opts.start_seq, blocks: fw.blocks().len() }),
+    };
+    log_retries(&result, stats);
+    result
+}
+
+/**
+ * `program_fw` for an image read block by block from `reader`, for images too large to hold in
+ * memory. Parses like `parse_fw_blocks`, but a bad block only shows once the ones before it went
+ * out, and the whole-image checks of `validate_fw` and `DownloadOptions::sha256` don't apply
+ */
+pub fn program_fw_streaming<R, B>(handle: &B, reader: R, opts: &DownloadOptions) -> Result<DownloadStats, FwError>
+where
+    R: Read + Seek,
+    B: BulkTransport,
+{
+    let mut blocks = FwBlockReader::new(reader)?;
+    span!("program_fw_streaming", bytes = blocks.size());
+    let mut skipped_bytes = 0;
+    for index in 0..opts.start_seq as usize {
+        match blocks.next() {
+            Some(Ok(block)) if !block.is_last() => skipped_bytes += std::mem::size_of::<FWHeader>() + block.data.len(),
+            Some(Err(e)) => return Err(e),
+            Some(Ok(_)) | None => return Err(FwError::StartSeqOutOfRange { start_seq: opts.start_seq, blocks: index + 1 }),
+        }
+    }
+
+    let mut stats = DownloadStats::default();
+    let total_bytes = blocks.size();
+    let result = send_blocks(handle, blocks, total_bytes, skipped_bytes, opts, |_| {}, &mut stats);
+    log_retries(&result, &stats);
+    result.map(|()| stats)
+}
+
+/** Retries on a download that went through anyway hint at a bad cable or port */
+fn log_retries(result: &Result<(), FwError>, stats: &DownloadStats) {
+    match result {
+        Ok(()) if stats.retries_used > 0 => warn!("Download needed {} retries, the USB link may be unreliable", stats.retries_used),
+        Ok(()) => debug!("Download needed no retries"),
+        Err(_) => warn!("Download failed after {} retries", stats.retries_used),
+    }
+}
+
+/**
+ * Send `blocks`, the image from seq `DownloadOptions::start_seq` on. `skipped_bytes` are those of
+ * the blocks before it, `total_bytes` the whole image's, both only feed the progress events
+ */
+fn send_blocks<B, F, I, K>(handle: &B, blocks: I, total_bytes: usize, skipped_bytes: usize, opts: &DownloadOptions, mut progress: F, stats: &mut DownloadStats) -> Result<(), FwError>
+where
+    B: BulkTransport,
+    F: FnMut(ProgressEvent),
+    I: IntoIterator<Item = Result<K, FwError>>,
+    K: Borrow<FwBlock>,
+{
+    if opts.start_seq > 0 {
+        warn!("Starting at seq {0}, skipping {0} blocks", opts.start_seq);
+    }
+    let mut bytes_sent = skipped_bytes;
+    let mut seq_num = opts.start_seq;
+    let start = Instant::now();
+    let mut block_times = vec![];
+    let mut window = TxWindow::default();
+    let mut last_ack: Option<(u32, Instant)> = None;
+
+    for block in blocks {
+        let block = block?;
+        let block = block.borrow();
+        let fw_header = &block.header;
+        debug!("FW Header: {fw_header:?}");
+        if !block.data.is_empty() {
+            let len = block.data.len().min(RESPONSE_DUMP_LEN);
+            debug!("Block {seq_num} data:\n{}", hexdump(&block.data[..len], 0));
+        }
+
+        check_block_crc(block, seq_num, opts)?;
+        let mut send_buffer = block_buffer(block, seq_num)?;
+        pad_buffer(&mut send_buffer, seq_num, opts)?;
+        span!("block", seq = seq_num, bytes = send_buffer.len());
+
+        let mut retries = opts.retries;
+        let mut last_error = None;
+        // Fresh for every block, so a success resets the backoff
+        let mut backoff = opts.retry_base;
+        let block_start = Instant::now();
+        let response = loop {
+            check_abort(opts)?;
+            if let Some(deadline) = opts.deadline.filter(|deadline| start.elapsed() >= *deadline) {
+                return Err(FwError::DeadlineExceeded { seq: seq_num, deadline });
+            }
+            let since_ack = last_ack.map_or(start, |(_, acked)| acked).elapsed();
+            if let Some(timeout) = opts.stall_timeout.filter(|timeout| since_ack >= *timeout) {
+                return Err(FwError::Stalled { last_seq: last_ack.map(|(seq, _)| seq), timeout });
+            }
+            if retries == 0 {
+                return Err(FwError::RetriesExhausted { seq: seq_num, last_block: block.is_last(), last_error: last_error.map(Box::new) });
+            }
+
+            // Send block
+            debug!("Sending packet, seq: {seq_num}");
+            let acquired = window.try_acquire();
+            debug_assert!(acquired, "lockstep download exceeded the TX watermark");
+            let result = write_block(handle, &send_buffer, opts)
+                .and_then(|()| if block.is_last() { write_zlp(handle, opts) } else { Ok(()) })
+                .map(|()| read_sync(handle, opts));
+            window.release();
+            match result {
+                Ok(Ok(response)) => break Some(response),
+                Ok(Err(e)) if block.is_last() && is_lost_last_ack(&e) => {
+                    accept_lost_last_ack(&e, opts)?;
+                    break None;
+                },
+                Ok(Err(e)) | Err(e) if e.is_retryable() => {
+                    warn!("Failed when sending packet: {e}");
+                    retries -= 1;
+                    stats.retries_used += 1;
+                    progress(ProgressEvent::Retry { seq: seq_num, retries_left: retries, bytes_sent, total_bytes });
+                    last_error = Some(e);
+                    sleep(backoff.min(opts.retry_max));
+                    backoff = backoff.saturating_mul(2);
+                },
+                Ok(Err(e)) | Err(e) => return Err(e),
+            }
+        };
+
+        last_ack = Some((seq_num, Instant::now()));
+        bytes_sent += std::mem::size_of::<FWHeader>() + block.data.len();
+        if let Some((sync_header, recv_buffer)) = response {
+            check_sync(&sync_header, &recv_buffer, seq_num)?;
+            progress(ProgressEvent::BlockAcked { seq: seq_num, bytes_sent, total_bytes });
+        }
+        stats.blocks_sent += 1;
+        stats.bytes_sent += send_buffer.len() as u64;
+
+        // Retries and their backoff count towards the block
+        let block_time = block_start.elapsed();
+        if block_time > SLOW_BLOCK_THRESHOLD {
+            debug!("Slow block {seq_num} (cmd {:#x}, {} bytes): {block_time:.2?}", fw_header.dnld_cmd, block.data.len());
+        }
+        block_times.push(block_time);
+
+        if block.is_last() {
+            info!("Last block - finished!");
+            debug!("Block send+ack times:\n{}", block_time_histogram(&block_times));
+            return Ok(());
+        }
+
+        if !opts.block_delay.is_zero() {
+            sleep(opts.block_delay);
+        }
+
+        seq_num = next_seq(seq_num)?;
+    }
+
+    Err(FwError::MissingLastBlock)
+}
+
+/** `FwError::Interrupted` once `DownloadOptions::abort` got set */
+pub(crate) fn check_abort(opts: &DownloadOptions) -> Result<(), FwError> {
+    check_aborted(opts.abort.as_deref())
+}
+
+/** `FwError::Interrupted` once `abort` got set */
+pub(crate) fn check_aborted(abort: Option<&AtomicBool>) -> Result<(), FwError> {
+    match abort {
+        Some(abort) if abort.load(Ordering::Relaxed) => Err(FwError::Interrupted),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * `FwBlock::verify_crc` before sending a block. At debug level the declared and computed CRCs
+ * get logged for every block, with the variants and coverages that would have matched
+ */
+pub(crate) fn check_block_crc(block: &FwBlock, seq: u32, opts: &DownloadOptions) -> Result<(), FwError> {
+    if !block.has_crc() {
+        return Ok(());
+    }
+
+    if log::log_enabled!(log::Level::Debug) {
+        let computed = block.compute_crc(opts.crc_variant, CrcCoverage::Data);
+        let matches: Vec<String> = block.crc_matches().iter().map(|(variant, coverage)| format!("{variant:?} over {coverage:?}")).collect();
+        debug!("Block {seq} crc: header {:#010x}, computed {computed:#010x} ({:?}), matches: {}",
+            block.header.crc,
+            opts.crc_variant,
+            if matches.is_empty() { "none".to_string() } else { matches.join(", ") });
+    }
+
+    match block.verify_crc(seq, opts.crc_variant) {
+        Err(e) if opts.allow_crc_mismatch && !opts.strict => {
+            warn!("{e}, sending it anyway");
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+/** Warn about `condition`, or fail with it in strict mode */
+pub(crate) fn soft_failure(opts: &DownloadOptions, condition: String) -> Result<(), FwError> {
+    if opts.strict {
+        return Err(FwError::StrictViolation(condition));
+    }
+    warn!("{condition}");
+    Ok(())
+}
+
+/** One write/read exchange of the chip-rev handshake */
+fn chip_rev_exchange<B: BulkTransport>(handle: &B, opts: &DownloadOptions) -> Result<UsbAckPkt, FwError> {
+    let send_buf = vec![0u8; CHIP_REV_TX_BUF_SIZE];
+    let mut recv_buf = vec![0u8; opts.rx_buf.unwrap_or(CHIP_REV_RX_BUF_SIZE)];
+    handle.write_bulk(opts.ep_out, &send_buf, opts.timeout).map_err(|e| transfer_error(e, FwError::UsbWrite))?;
+    let received = handle.read_bulk(opts.ep_in, &mut recv_buf, opts.timeout).map_err(|e| transfer_error(e, FwError::UsbRead))?;
+
+    let expected = std::mem::size_of::<UsbAckPkt>();
+    if received < expected {
+        return Err(FwError::ShortResponse { got: received, expected });
+    }
+    debug!("Chip-rev response:\n{}", hexdump(&recv_buf[..received.min(RESPONSE_DUMP_LEN)], 0));
+    Ok(UsbAckPkt::read(&mut Cursor::new(&recv_buf[..received]))?)
+}
+
+/**
+ * Chip revision handshake, returns the detected revision.
+ * Transient failures are retried up to `DownloadOptions::chip_rev_retries` attempts, with the block retry backoff
+ */
+pub fn check_chip_rev<B: BulkTransport>(handle: &B, chip: MarvellChip, opts: &DownloadOptions) -> Result<ChipRev, FwError> {
+    let quirk = chip.quirks().ack_extend;
+    let extend_hdr = opts.extend_hdr.map_or(quirk >> 16, u32::from);
+    let extend_ver = opts.extend_ver.map_or(quirk & 0xffff, u32::from);
+    let extend = (extend_hdr << 16) | extend_ver;
+
+    let mut attempts = 0;
+    let mut backoff = opts.retry_base;
+    let pkt = loop {
+        check_abort(opts)?;
+        attempts += 1;
+        match chip_rev_exchange(handle, opts) {
+            Ok(pkt) => break pkt,
+            Err(e) if e.is_retryable() && attempts < opts.chip_rev_retries => {
+                warn!("Chip-rev handshake failed: {e}");
+                sleep(backoff.min(opts.retry_max));
+                backoff = backoff.saturating_mul(2);
+            },
+            Err(e) if e.is_retryable() => return Err(FwError::ChipRevFailed { attempts, last_error: Box::new(e) }),
+            Err(e) => return Err(e),
+        }
+    };
+    debug!("Chiprev resp: {pkt:?}");
+    // The reference driver never looks at it, a mismatch is only suspicious
+    let ack_seq = chip.quirks().ack_seq;
+    debug!("Chip-rev ack seq: {}", pkt.seq);
+    if pkt.seq != ack_seq {
+        soft_failure(opts, format!("Chip-rev response echoes seq {}, expected {ack_seq}, the handshake may be out of sync", pkt.seq))?;
+    }
+    if pkt.winner() != SyncWinner::Host {
+        soft_failure(opts, format!("Host did not win the download sync (ack_winner {:#010x}), the download may fail", pkt.ack_winner))?;
+    }
+
+    if pkt.extend == extend {
+        let rev = ChipRev::from_raw(pkt.chip_rev);
+        if let ChipRev::Unknown(raw) = rev {
+            soft_failure(opts, format!("Unknown chip revision {raw:#010x}"))?;
+        }
+        info!("Chip Rev: {rev} (From Response)");
+        Ok(rev)
+    } else {
+        // No extended ack, fall back to A0 like the reference driver
+        soft_failure(opts, format!("Chip-rev response has no extended ack ({:#010x}), assuming {}", pkt.extend, ChipRev::A0))?;
+        info!("Chip Rev: {}", ChipRev::A0);
+        Ok(ChipRev::A0)
+    }
+}
+
+/** What `download_fw` did, `download_fw_with_report` fills it in as far as a failed download got */
+#[derive(Debug, Clone, Default)]
+pub struct DownloadReport {
+    /** Chip revision from the handshake, `None` for chips without one */
+    pub chip_rev: Option<ChipRev>,
+    pub stats: DownloadStats,
+    /** Time spent in `program_fw` */
+    pub elapsed: Duration,
+    /** Device was already running its firmware, so nothing was sent */
+    pub skipped: bool,
+}
+
+/** Where `download_fw` takes the firmware image from */
+#[derive(Debug, Clone, Copy)]
+pub enum FwSource<'a> {
+    /** Image file, or `STDIN_PATH` */
+    File(&'a str),
+    /** Already loaded image, e.g. to flash the same one to several devices */
+    Loaded(&'a Firmware),
+    /**
+     * Several loaded images downloaded one after the other over the same claimed interface,
+     * e.g. a helper image and then the main firmware. Each is a full download of its own ending
+     * in its last block, `DownloadOptions::start_seq` only applies to the first
+     */
+    Sequence(&'a [Firmware]),
+    /** Directory searched by `find_fw_in_dir`, once the chip revision is known */
+    Dir(&'a Path),
+}
+
+/** `read_fw`, written to `DownloadOptions::dump_firmware` and checked against `DownloadOptions::sha256` */
+pub fn read_fw_checked(path: &str, opts: &DownloadOptions) -> Result<Vec<u8>, FwError> {
+    let fw = read_fw(path)?;
+    info!("Read fw {path} ({} bytes)", fw.len());
+    // Before any check, so a rejected image can be looked at too
+    if let Some(dump_path) = &opts.dump_firmware {
+        std::fs::write(dump_path, &fw)?;
+        info!("Dumped fw to {}", dump_path.display());
+    }
+    if let Some(expected) = &opts.sha256 {
+        verify_sha256(&fw, expected)?;
+    }
+    Ok(fw)
+}
+
+/** `read_fw_checked` and parsed, as `download_fw` loads a `FwSource::File` */
+pub fn load_fw(path: &str, opts: &DownloadOptions) -> Result<Firmware, FwError> {
+    let fw = Firmware::parse(path, &read_fw_checked(path, opts)?)?;
+
+    for index in fw.empty_blocks() {
+        if opts.strict {
+            return Err(FwError::EmptyBlock { index });
+        }
+        warn!("Firmware block {index} has no data, the image may be corrupt");
+    }
+    if opts.check_base_addr {
+        for (index, anomaly) in fw.base_addr_anomalies() {
+            soft_failure(opts, format!("Firmware block {index}: {anomaly}, the image may be misparsed"))?;
+        }
+    }
+    Ok(fw)
+}
+
+pub fn download_fw<T: rusb::UsbContext>(chip: MarvellChip, device: rusb::Device<T>, source: FwSource, opts: &DownloadOptions) -> Result<DownloadReport, FwError> {
+    let mut report = DownloadReport::default();
+    download_fw_with_report(chip, device, source, opts, &mut report).map(|()| report)
+}
+
+/**
+ * `download_fw`, filling in `report` as it goes. On failure it holds the chip revision and
+ * the acked blocks and retries up to that point
+ */
+pub fn download_fw_with_report<T: rusb::UsbContext>(chip: MarvellChip, device: rusb::Device<T>, source: FwSource, opts: &DownloadOptions, report: &mut DownloadReport) -> Result<(), FwError> {
+    info!("Starting fw download for {:?}", chip);
+    if let Some(rx_buf) = opts.rx_buf.filter(|size| *size > RX_BUF_WARN_SIZE) {
+        warn!("Receive buffer of {rx_buf} bytes is unusually large, responses are far smaller");
+    }
+    if let Some(ep_out) = opts.ep_out_override.filter(|ep| ep & rusb::constants::LIBUSB_ENDPOINT_IN != 0) {
+        return Err(FwError::EndpointDirection { address: ep_out, expected: rusb::Direction::Out });
+    }
+    if let Some(ep_in) = opts.ep_in_override.filter(|ep| ep & rusb::constants::LIBUSB_ENDPOINT_IN == 0) {
+        return Err(FwError::EndpointDirection { address: ep_in, expected: rusb::Direction::In });
+    }
+    // Fail on a bad file before touching the device
+    let images = match source {
+        FwSource::File(path) => Some(vec![Cow::Owned(load_fw(path, opts)?)]),
+        FwSource::Loaded(fw) => Some(vec![Cow::Borrowed(fw)]),
+        FwSource::Sequence([]) => return Err(FwError::InvalidFirmware { name: "firmware sequence".into(), reason: "no images".into() }),
+        FwSource::Sequence(fws) => Some(fws.iter().map(Cow::Borrowed).collect()),
+        FwSource::Dir(_) => None,
+    };
+
+    match read_boot_state(&device, chip)? {
+        Some(BootState::FwDownload) => debug!("Device is in download mode"),
+        Some(BootState::FwReady) => {
+            soft_failure(opts, "Device is already running its firmware, skipping the download".into())?;
+            report.skipped = true;
+            return Ok(());
+        },
+        None => debug!("Can't tell the boot state of {chip:?} from its pid, assuming download mode"),
+    }
+
+    let (claimed, opts) = claim_for_download(&device, opts)?;
+    let handle = claimed.handle();
+    let opts = &opts;
+
+    let chip_rev = chip_rev_handshake(handle, chip, opts)?;
+    report.chip_rev = chip_rev;
+
+    let images = match (images, source) {
+        (Some(images), _) => images,
+        (None, FwSource::Dir(dir)) => {
+            let path = find_fw_in_dir(dir, chip, chip_rev)?;
+            vec![Cow::Owned(load_fw(&path.to_string_lossy(), opts)?)]
+        },
+        (None, FwSource::File(path)) => vec![Cow::Owned(load_fw(path, opts)?)],
+        (None, FwSource::Loaded(fw)) => vec![Cow::Borrowed(fw)],
+        (None, FwSource::Sequence(fws)) => fws.iter().map(Cow::Borrowed).collect(),
+    };
+
+    // Later images of a sequence may be of another kind, e.g. after a helper
+    if let Some(fw) = images.first() {
+        check_first_cmd(chip, fw, opts)?;
+    }
+
+    let start = Instant::now();
+    let later_opts = DownloadOptions { start_seq: 0, ..opts.clone() };
+    for (index, fw) in images.iter().enumerate() {
+        let opts = match index {
+            0 => opts,
+            _ => {
+                sleep(opts.image_delay);
+                if opts.rehandshake && chip.quirks().chip_rev_handshake && !opts.skip_chip_rev {
+                    check_chip_rev(handle, chip, opts)?;
+                }
+                &later_opts
+            },
+        };
+        if images.len() > 1 {
+            info!("Sending image {} of {}: {}", index + 1, images.len(), fw.name());
+        }
+
+        match opts.pipelined {
+            true => {
+                let image_stats = program_fw_async(handle, fw, opts)?;
+                report.stats.blocks_sent += image_stats.blocks_sent;
+                report.stats.bytes_sent += image_stats.bytes_sent;
+                report.stats.retries_used += image_stats.retries_used;
+            },
+            // Counts into the report as it goes, so a failed image still leaves what got through
+            false => program_fw_into(handle, fw, opts, |_| {}, &mut report.stats)?,
+        }
+    }
+    report.elapsed = start.elapsed();
+    let (stats, elapsed) = (report.stats, report.elapsed);
+    info!("Sent {} blocks, {} bytes with {} retries in {elapsed:.2?} ({:.1} KiB/s)",
+        stats.blocks_sent,
+        stats.bytes_sent,
+        stats.retries_used,
+        stats.bytes_sent as f64 / 1024.0 / elapsed.as_secs_f64().max(f64::EPSILON));
+
+    let mut handle = claimed.release()?;
+
+    if opts.reset {
+        match handle.reset() {
+            // The device usually drops off the bus while re-enumerating
+            Ok(()) | Err(rusb::Error::NoDevice) => info!("Device reset"),
+            Err(e) => return Err(FwError::Usb(e)),
+        }
+    }
+
+    if let Some(timeout) = opts.verify {
+        if chip.runtime_product_id().is_none() {
+            return Err(FwError::UnknownRuntimePid(chip));
+        }
+        // Rebadged modules come back under their own vendor id too
+        let vendor_id = device.device_descriptor()?.vendor_id();
+        match wait_boot_state(device.context(), vendor_id, chip, device.bus_number(), timeout, opts.abort.as_deref())? {
+            Some(BootState::FwReady) => {},
+            state => return Err(FwError::NotReady { state, waited: timeout }),
+        }
+    }
+
+    if let Some(timeout) = opts.confirm_reboot {
+        let vendor_id = device.device_descriptor()?.vendor_id();
+        confirm_reboot(device.context(), vendor_id, chip, device.bus_number(), timeout, opts.abort.as_deref())?;
+    }
+
+    Ok(())
+}
+
+/** Open `device` and claim its download interface, returning `opts` with the bulk endpoints to use filled in */
+fn claim_for_download<T: rusb::UsbContext>(device: &rusb::Device<T>, opts: &DownloadOptions) -> Result<(transport::ClaimedInterface<T>, DownloadOptions), FwError> {
+    let mut handle = device.open()?;
+
+    // Ignore error, windows will throw one
+    let _ = handle.set_auto_detach_kernel_driver(true);
+
+    if !transport::has_interface(device, opts.interface)? {
+        return Err(FwError::NoSuchInterface(opts.interface));
+    }
+    // Released on every early return from here on
+    let mut claimed = transport::ClaimedInterface::claim(handle, opts.interface, opts.detach)?;
+    if let Some(alt_setting) = opts.alt_setting {
+        if !transport::has_alt_setting(device, opts.interface, alt_setting)? {
+            return Err(FwError::NoSuchAltSetting { interface: opts.interface, alt_setting });
+        }
+        info!("Selecting alternate setting {alt_setting} of interface {}", opts.interface);
+        claimed.handle_mut().set_alternate_setting(opts.interface, alt_setting)?;
+    }
+
+    let mut opts = opts.clone();
+    match transport::find_bulk_endpoints(device, opts.interface, opts.alt_setting.unwrap_or(0)) {
+        Some((ep_out, ep_in)) => {
+            debug!("Bulk endpoints: OUT {ep_out:#04x}, IN {ep_in:#04x}");
+            opts.ep_out = ep_out;
+            opts.ep_in = ep_in;
+        },
+        None => soft_failure(&opts, format!("Couldn't detect bulk endpoints, falling back to OUT {:#04x}, IN {:#04x}", opts.ep_out, opts.ep_in))?,
+    }
+    if let Some(ep_out) = opts.ep_out_override {
+        info!("Forcing bulk OUT endpoint {ep_out:#04x}");
+        opts.ep_out = ep_out;
+    }
+    if let Some(ep_in) = opts.ep_in_override {
+        info!("Forcing bulk IN endpoint {ep_in:#04x}");
+        opts.ep_in = ep_in;
+    }
+    if opts.pad_out == OutPadding::MaxPacket {
+        match transport::max_packet_size(device, opts.interface, opts.alt_setting.unwrap_or(0), opts.ep_out) {
+            Some(size) => {
+                debug!("Bulk OUT max packet size: {size}");
+                opts.ep_out_max_packet = size;
+            },
+            None => soft_failure(&opts, format!("Couldn't read the max packet size of endpoint {:#04x}, padding to {}", opts.ep_out, opts.ep_out_max_packet))?,
+        }
+    }
+    Ok((claimed, opts))
+}
+
+/** Whether `fw` starts with the `ChipQuirks::first_dnld_cmd` of `chip`, a soft failure if not */
+fn check_first_cmd(chip: MarvellChip, fw: &Firmware, opts: &DownloadOptions) -> Result<(), FwError> {
+    let (Some(expected), Some(first)) = (chip.quirks().first_dnld_cmd, fw.blocks().first()) else {
+        return Ok(());
+    };
+    if first.header.dnld_cmd != expected {
+        soft_failure(opts, format!("{} starts with dnld_cmd {:#x} instead of the {expected:#x} {chip:?} images start with, it may be for another chip", fw.name(), first.header.dnld_cmd))?;
+    }
+    Ok(())
+}
+
+/** `check_chip_rev`, unless `DownloadOptions::skip_chip_rev` or the chip's quirks skip it */
+fn chip_rev_handshake<B: BulkTransport>(handle: &B, chip: MarvellChip, opts: &DownloadOptions) -> Result<Option<ChipRev>, FwError> {
+    if opts.skip_chip_rev {
+        warn!("Skipping chip-rev handshake as requested");
+        Ok(None)
+    } else if chip.quirks().chip_rev_handshake {
+        check_chip_rev(handle, chip, opts).map(Some)
+    } else {
+        debug!("Skipping chip-rev handshake for {chip:?}");
+        Ok(None)
+    }
+}
+
+/** What `probe_device` found */
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeReport {
+    /** As `read_boot_state` tells it from the pid */
+    pub boot_state: Option<BootState>,
+    /** `None` if the handshake was skipped */
+    pub chip_rev: Option<ChipRev>,
+    /** Bulk endpoints a download would use */
+    pub ep_out: u8,
+    pub ep_in: u8,
+}
+
+/**
+ * Pre-flight of `download_fw`: open `device`, claim its interface and run the chip-rev handshake,
+ * then release it again without sending any firmware. A device already running its firmware has no
+ * bootloader left to answer the handshake, it is skipped with a warning then
+ */
+pub fn probe_device<T: rusb::UsbContext>(chip: MarvellChip, device: &rusb::Device<T>, opts: &DownloadOptions) -> Result<ProbeReport, FwError> {
+    let boot_state = read_boot_state(device, chip)?;
+    let (claimed, opts) = claim_for_download(device, opts)?;
+    let chip_rev = match boot_state {
+        Some(BootState::FwReady) => {
+            warn!("Device is already running its firmware, skipping the chip-rev handshake");
+            None
+        },
+        _ => chip_rev_handshake(claimed.handle(), chip, &opts)?,
+    };
+    claimed.release()?;
+
+    Ok(ProbeReport { boot_state, chip_rev, ep_out: opts.ep_out, ep_in: opts.ep_in })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use super::*;
+
+    /**
+     * Scripted device. Writes fail with `write_errors` first, reads return `reads` first,
+     * after that every read acks the last block written
+     */
+    #[derive(Default)]
+    struct MockTransport {
+        writes: Mutex<Vec<Vec<u8>>>,
+        write_errors: Mutex<VecDeque<rusb::Error>>,
+        reads: Mutex<VecDeque<rusb::Result<Vec<u8>>>>,
+    }
+
+    impl MockTransport {
+        fn with_reads(reads: impl IntoIterator<Item = rusb::Result<Vec<u8>>>) -> Self {
+            MockTransport { reads: Mutex::new(reads.into_iter().collect()), ..Default::default() }
+        }
+
+        /** Sequence numbers of the blocks that went out, in order */
+        fn written_seqs(&self) -> Vec<u32> {
+            self.writes.lock().unwrap().iter().filter_map(|write| seq_of(write)).collect()
+        }
+    }
+
+    impl BulkTransport for MockTransport {
+        fn write_bulk(&self, _ep: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            if let Some(e) = self.write_errors.lock().unwrap().pop_front() {
+                return Err(e);
+            }
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn read_bulk(&self, _ep: u8, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+            let response = match self.reads.lock().unwrap().pop_front() {
+                Some(response) => response?,
+                None => {
+                    let writes = self.writes.lock().unwrap();
+                    sync(0, writes.iter().rev().find_map(|write| seq_of(write)).unwrap_or(0))
+                },
+            };
+            let len = response.len().min(buf.len());
+            buf[..len].copy_from_slice(&response[..len]);
+            Ok(len)
+        }
+    }
+
+    /** `FWData::seq_num` of a block write, `None` for other writes */
+    fn seq_of(write: &[u8]) -> Option<u32> {
+        Some(u32::from_le_bytes(write.get(16..20)?.try_into().unwrap()))
+    }
+
+    /** Wire bytes of a `FWSyncHeader` */
+    fn sync(cmd: u32, seq_num: u32) -> Vec<u8> {
+        [cmd.to_le_bytes(), seq_num.to_le_bytes()].concat()
+    }
+
+    /** Four blocks: data, CMD 7, data, last, see `selftest` */
+    fn sample_fw() -> Firmware {
+        Firmware::from_bytes(selftest::SAMPLE_FW).unwrap()
+    }
+
+    #[test]
+    fn program_fw_reaches_last_block() {
+        let transport = MockTransport::default();
+        let stats = program_fw(&transport, &sample_fw(), &DownloadOptions::default()).unwrap();
+        assert_eq!(transport.written_seqs(), [0, 1, 2, 3]);
+        assert_eq!(stats, DownloadStats { blocks_sent: 4, bytes_sent: sample_fw().size() as u64 + 4 * 4, retries_used: 0 });
+    }
+
+    #[test]
+    fn program_fw_fails_on_nonzero_cmd() {
+        let transport = MockTransport::with_reads([Ok(sync(0, 0)), Ok(sync(0x2, 1))]);
+        let result = program_fw(&transport, &sample_fw(), &DownloadOptions::default());
+        assert!(matches!(result, Err(FwError::SyncError { seq: 1, code: 0x2 })), "{result:?}");
+    }
+
+    #[test]
+    fn program_fw_fails_on_wrong_seq() {
+        let transport = MockTransport::with_reads([Ok(sync(0, 5))]);
+        let result = program_fw(&transport, &sample_fw(), &DownloadOptions::default());
+        assert!(matches!(result, Err(FwError::SeqMismatch { got: 5, expected: 0 })), "{result:?}");
+    }
+
+    #[test]
+    fn program_fw_retries_failed_writes() {
+        let transport = MockTransport::default();
+        transport.write_errors.lock().unwrap().extend([rusb::Error::Timeout, rusb::Error::Io]);
+        let stats = program_fw(&transport, &sample_fw(), &DownloadOptions::default()).unwrap();
+        assert_eq!(transport.written_seqs(), [0, 1, 2, 3]);
+        assert_eq!(stats.retries_used, 2);
+    }
+
+    /** Acks for the first three sample blocks, then `last` for the final one */
+    fn acks_then(last: impl IntoIterator<Item = rusb::Result<Vec<u8>>>) -> MockTransport {
+        MockTransport::with_reads((0..3).map(|seq| Ok(sync(0, seq))).chain(last))
+    }
+
+    #[test]
+    fn lost_last_ack_counts_as_success() {
+        let transport = acks_then([Err(rusb::Error::Timeout)]);
+        let stats = program_fw(&transport, &sample_fw(), &DownloadOptions::default()).unwrap();
+        assert_eq!(stats.blocks_sent, 4);
+        assert_eq!(stats.retries_used, 0);
+    }
+
+    #[test]
+    fn lost_last_ack_fails_in_strict_mode() {
+        let transport = acks_then([Err(rusb::Error::NoDevice)]);
+        let result = program_fw(&transport, &sample_fw(), &DownloadOptions { strict: true, ..Default::default() });
+        assert!(matches!(result, Err(FwError::StrictViolation(_))), "{result:?}");
+    }
+
+    #[test]
+    fn exhausted_last_block_is_reported_as_such() {
+        let transport = acks_then(std::iter::repeat_n(Err(rusb::Error::Io), 3));
+        let result = program_fw(&transport, &sample_fw(), &DownloadOptions::default());
+        assert!(matches!(&result, Err(FwError::RetriesExhausted { seq: 3, last_block: true, last_error: Some(e) }) if matches!(**e, FwError::UsbRead(rusb::Error::Io))), "{result:?}");
+        assert_eq!(transport.written_seqs(), [0, 1, 2, 3, 3, 3]);
+    }
+
+    #[test]
+    fn failed_download_keeps_its_stats() {
+        let transport = acks_then(std::iter::repeat_n(Err(rusb::Error::Io), 3));
+        let mut stats = DownloadStats::default();
+        assert!(program_fw_into(&transport, &sample_fw(), &DownloadOptions::default(), |_| {}, &mut stats).is_err());
+        assert_eq!((stats.blocks_sent, stats.retries_used), (3, 3));
+    }
+
+    #[test]
+    fn boot_state_needs_a_known_runtime_pid() {
+        let avastar = MarvellChip::Avastar88W8897;
+        assert_eq!(avastar.boot_state(0x2045), Some(BootState::FwDownload));
+        assert_eq!(avastar.boot_state(0x2046), Some(BootState::FwReady));
+        assert_eq!(avastar.boot_state(0x2040), None);
+        // An 8897 waiting for its download is no sign an 8782U booted
+        assert_eq!(MarvellChip::Avastar88W8782U.boot_state(0x2045), None);
+        assert_eq!(MarvellChip::Generic(0x2050).boot_state(0x2051), None);
+    }
+
+    #[test]
+    fn runtime_pid_is_detected_as_fw_ready() {
+        let chip = MarvellChip::from_runtime_product_id(0x2046);
+        assert_eq!(chip, Some(MarvellChip::Avastar88W8897));
+        assert_eq!(chip.and_then(|chip| chip.boot_state(0x2046)), Some(BootState::FwReady));
+        // Download-mode pids aren't runtime ones
+        assert_eq!(MarvellChip::from_runtime_product_id(0x2045), None);
+        assert_eq!(MarvellChip::from_runtime_product_id(0x2040), None);
+    }
+
+    /** Wire bytes of a `UsbAckPkt` */
+    fn ack(ack_winner: u32, extend: u32, chip_rev: u32) -> Vec<u8> {
+        [ack_winner, 0, extend, chip_rev].map(u32::to_le_bytes).concat()
+    }
+
+    #[test]
+    fn chip_rev_parses_full_ack() {
+        let extend = (EXTEND_HDR << 16) | EXTEND_V1;
+        let transport = MockTransport::with_reads([Ok(ack(0, extend, USB8797_B0))]);
+        assert_eq!(check_chip_rev(&transport, MarvellChip::Avastar88W8897, &DownloadOptions::default()).unwrap(), ChipRev::B0);
+    }
+
+    #[test]
+    fn truncated_ack_is_a_short_response() {
+        let full = ack(0, (EXTEND_HDR << 16) | EXTEND_V1, USB8797_B0);
+        let transport = MockTransport::with_reads([Ok(full[..10].to_vec())]);
+        let result = chip_rev_exchange(&transport, &DownloadOptions::default());
+        assert!(matches!(result, Err(FwError::ShortResponse { got: 10, expected: 16 })), "{result:?}");
+
+        // Retried like any short read, then reported with it as the cause
+        let transport = MockTransport::with_reads(std::iter::repeat_n(Ok(full[..10].to_vec()), 3));
+        let result = check_chip_rev(&transport, MarvellChip::Avastar88W8897, &DownloadOptions::default());
+        assert!(matches!(&result, Err(FwError::ChipRevFailed { attempts: 3, last_error }) if matches!(**last_error, FwError::ShortResponse { .. })), "{result:?}");
+    }
+
+    #[test]
+    fn ack_winner_decodes_bit_31() {
+        let parse = |ack_winner| UsbAckPkt::read(&mut Cursor::new(ack(ack_winner, 0, 0))).unwrap();
+        assert_eq!(parse(0).winner(), SyncWinner::Host);
+        assert_eq!(parse(0x7fff_ffff).winner(), SyncWinner::Host);
+        assert_eq!(parse(ACK_NOT_WINNER).winner(), SyncWinner::Other);
+        assert_eq!(parse(ACK_NOT_WINNER | 1).winner(), SyncWinner::Other);
+    }
+
+    #[test]
+    fn short_sync_response_is_retried() {
+        let transport = MockTransport::with_reads([Ok(vec![0; 4]), Ok(vec![])]);
+        program_fw(&transport, &sample_fw(), &DownloadOptions::default()).unwrap();
+        // Block 0 went out once per attempt
+        assert_eq!(transport.written_seqs(), [0, 0, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn seq_num_overflow_is_an_error() {
+        assert_eq!(next_seq(u32::MAX - 1).unwrap(), u32::MAX);
+        assert!(matches!(next_seq(u32::MAX), Err(FwError::SeqOverflow)));
+    }
+
+    #[test]
+    fn cmd7_buffer_ignores_data_length() {
+        let block = FwBlock { header: FWHeader { dnld_cmd: FW_CMD_7, base_addr: 0, data_length: 0x20, crc: 0 }, data: vec![] };
+        let buffer = block_buffer(&block, 1).unwrap();
+        assert_eq!(buffer.len(), fw_data_xmit_size(0) as usize);
+        // data_length still goes out as the header says
+        assert_eq!(buffer[8..12], 0x20u32.to_le_bytes());
+    }
+
+    #[test]
+    fn streaming_matches_buffered() {
+        let fw = sample_fw();
+        for start_seq in 0..=4 {
+            let opts = DownloadOptions { start_seq, ..Default::default() };
+            let buffered = MockTransport::default();
+            let streaming = MockTransport::default();
+            let expected = program_fw(&buffered, &fw, &opts);
+            let result = program_fw_streaming(&streaming, Cursor::new(selftest::SAMPLE_FW), &opts);
+            assert_eq!(format!("{result:?}"), format!("{expected:?}"), "start_seq {start_seq}");
+            assert_eq!(*streaming.writes.lock().unwrap(), *buffered.writes.lock().unwrap(), "start_seq {start_seq}");
+        }
+    }
+
+    #[test]
+    fn padding_past_the_tx_buffer_is_rejected() {
+        let block = FwBlock { header: FWHeader { dnld_cmd: FW_CMD_1, base_addr: 0, data_length: 580, crc: 0 }, data: vec![0; 580] };
+        let mut buffer = block_buffer(&block, 3).unwrap();
+        assert_eq!(buffer.len(), 600);
+
+        let max_packet = DownloadOptions { pad_out: OutPadding::MaxPacket, ep_out_max_packet: 512, ..Default::default() };
+        assert!(matches!(pad_buffer(&mut buffer, 3, &max_packet), Err(FwError::BlockTooLarge { index: 3, size: 1024, max: FW_DNLD_TX_BUF_SIZE })));
+        assert_eq!(buffer.len(), 600);
+
+        // 608 still fits
+        let pad_16 = DownloadOptions { pad_out: OutPadding::To(16), ..Default::default() };
+        pad_buffer(&mut buffer, 3, &pad_16).unwrap();
+        assert_eq!(buffer.len(), 608);
+    }
+}
@@ -1,284 +1,732 @@
-#![allow(dead_code)]
-
-use std::{io::{Cursor, Read, Seek, SeekFrom, Write}, time::Duration};
-use binrw::{
-    binrw,
-    BinRead,
-    BinWrite,
-};
-use std::thread::sleep;
-
-const DRIVER_USB_BULK_MSG_TIMEOUT: Duration = Duration::from_millis(100);
-
-const MARVELL_USB_FW_DNLD: u8 = 1;
-/** Boot state: FW ready */
-const MARVELL_USB_FW_READY: u8 = 2;
-
-/** CMD id for CMD7 */
-const FW_CMD_7: u32 = 0x00000007;
-
-/** High watermark for Tx data */
-const MVUSB_TX_HIGH_WMARK: u8 = 6;
-
-/** Number of Rx data URB */
-const MVUSB_RX_DATA_URB: u8 = 6;
-
-/* Transmit buffer size for chip revision check */
-const CHIP_REV_TX_BUF_SIZE: usize = 16;
-/* Receive buffer size for chip revision check */
-const CHIP_REV_RX_BUF_SIZE: usize = 2048;
-
-/* Extensions */
-const EXTEND_HDR: u32 = 0xAB95;
-const EXTEND_V1: u32 = 0x0001;
-
-/** USB8797 chip revision ID */
-const USB8797_A0: u32 = 0x00000000;
-const USB8797_B0: u32 = 0x03800010;
-
-/** Tx buffer size for firmware download*/
-const FW_DNLD_TX_BUF_SIZE: usize = 620;
-/** Rx buffer size for firmware download*/
-const FW_DNLD_RX_BUF_SIZE: usize = 2048;
-/** Max firmware retry */
-const MAX_FW_RETRY: u8 = 3;
-
-/** Firmware has last block */
-const FW_HAS_LAST_BLOCK: u32 = 0x00000004;
-
-/** Firmware data transmit size */
-fn fw_data_xmit_size(data_len: u32) -> u32 {
-    std::mem::size_of::<FWHeader>() as u32 + data_len + std::mem::size_of::<u32>() as u32
-}
-
-/** FWHeader */
-#[binrw]
-#[brw(little)]
-#[derive(Debug, Clone)]
-struct FWHeader {
-    /** FW download command */
-	dnld_cmd: u32,
-    /** FW base address */
-	base_addr: u32,
-    /** FW data length */
-	data_length: u32,
-    /** FW CRC */
-	crc: u32,
-}
-
-/** FWData */
-#[binrw]
-#[brw(little)]
-#[derive(Debug)]
-struct FWData {
-    /** FW data header */
-	fw_header: FWHeader,
-    /** FW data sequence number */
-	seq_num: u32,
-    /* FW data buffer */
-	// data: [u8; 2],
-}
-
-/** FWSyncHeader */
-#[binrw]
-#[brw(little)]
-#[derive(Debug)]
-struct FWSyncHeader {
-    /** FW sync header command */
-	cmd: u32,
-    /** FW sync header sequence number */
-	seq_num: u32,
-}
+mod cli;
+mod config;
 
-#[binrw]
-#[brw(little)]
-#[derive(Debug)]
-struct UsbAckPkt {
-    ack_winner: u32,
-    seq: u32,
-    extend: u32,
-    chip_rev: u32,
+use log::{debug, error, info, warn};
+use std::{cell::Cell, io::Write, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread::{self, sleep}, time::{Duration, Instant}};
+
+use rusb::UsbContext;
+use mrvl_fw_dloader::{probe_device, wait_boot_state, BootState, download_fw_with_report, load_fw, DownloadReport, FwSource, Firmware, parse_fw_blocks, read_fw_checked, DownloadOptions, FwError, MarvellChip, MARVELL_VENDOR_ID, selftest};
+
+use cli::{Cli, Command, LibusbLogLevel, Region};
+use serde::Serialize;
+
+/** Delay between bus scans while waiting for a device */
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/** Exit code after Ctrl-C, the shell convention of 128 + SIGINT */
+const EXIT_INTERRUPTED: i32 = 130;
+
+/** Least time `--repeat` waits for the device to come back in download mode, `--wait` can raise it */
+const REPEAT_WAIT: Duration = Duration::from_secs(30);
+
+thread_local! {
+    /** Bus and address of the device this thread is flashing, prefixed to log lines in `--all` mode */
+    static DEVICE_TAG: Cell<Option<(u8, u8)>> = const { Cell::new(None) };
 }
 
-#[repr(u8)]
-#[derive(Debug)]
-enum DriveUsbEp {
-    Ctrl = 0,
-    CmdEvent = 1,
-    Data = 2,
+/** Everything the `flash` subcommand needs, built from the command line by `cli` */
+#[derive(Debug, Default)]
+struct Args {
+    /** Images to send in order, empty with `fw_dir` */
+    fw_paths: Vec<String>,
+    fw_dir: Option<PathBuf>,
+    json: bool,
+    /** Opened by `main` into `DownloadOptions::sync_log` */
+    sync_log: Option<PathBuf>,
+    all: bool,
+    jobs: usize,
+    repeat: u32,
+    keep_going: bool,
+    /** From `--region-order`, one per `fw_paths` entry or empty */
+    regions: Vec<Region>,
+    /** Applied to the global context by `main` and to the `--all` workers' own contexts */
+    libusb_debug: Option<LibusbLogLevel>,
+    filter: DeviceFilter,
+    wait: Duration,
+    wait_ready: Option<Duration>,
+    opts: DownloadOptions,
 }
 
-#[warn(non_camel_case_types)]
+/** Narrows down which of the enumerated Marvell devices get used */
 #[derive(Debug)]
-enum MarvellChip {
-    Avastar88W8782U,
-    Avastar88W8897
+struct DeviceFilter {
+    /** Vendor id to scan for, `MARVELL_VENDOR_ID` unless rebadged modules are flashed */
+    vendor: u16,
+    bus: Option<u8>,
+    address: Option<u8>,
+    pid: Option<u16>,
 }
 
-fn read_fw(path: &str) -> Result<Vec<u8>, std::io::Error> {
-    let mut file = std::fs::File::open(path)?;
-    file.seek(SeekFrom::End(0))?;
-    let fsize = file.stream_position()?;
-    file.seek(SeekFrom::Start(0))?;
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        DeviceFilter { vendor: MARVELL_VENDOR_ID, bus: None, address: None, pid: None }
+    }
+}
 
-    let mut buf = vec![0u8; fsize as usize];
-    file.read_exact(&mut buf)?;
+impl DeviceFilter {
+    fn matches<T: UsbContext>(&self, device: &rusb::Device<T>) -> bool {
+        device.device_descriptor().is_ok_and(|desc| desc.vendor_id() == self.vendor)
+            && self.bus.is_none_or(|bus| bus == device.bus_number())
+            && self.address.is_none_or(|address| address == device.address())
+            && self.pid.is_none_or(|pid| device.device_descriptor().is_ok_and(|desc| desc.product_id() == pid))
+    }
 
-    Ok(buf)
+    fn is_set(&self) -> bool {
+        self.bus.is_some() || self.address.is_some() || self.pid.is_some()
+    }
 }
 
-fn program_fw<T: rusb::UsbContext>(handle: &rusb::DeviceHandle<T>, fw: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-    let mut data_len = 0;
-    let mut seq_num = 0;
-    let mut reader = Cursor::new(fw);
+fn list_devices(filter: &DeviceFilter) -> Result<(), FwError> {
+    info!("Scanning for vendor id {:#06x}", filter.vendor);
+    for device in rusb::devices()?.iter() {
+        let device_desc = device.device_descriptor()?;
+        if !filter.matches(&device) {
+            continue;
+        }
 
-    let mut retries = MAX_FW_RETRY;
+        let pid = device_desc.product_id();
+        let chip = match (MarvellChip::from_product_id(pid), MarvellChip::from_runtime_product_id(pid)) {
+            (Some(chip), _) => format!("{chip:?}"),
+            (None, Some(chip)) => format!("{chip:?} (firmware running)"),
+            (None, None) => format!("Unknown (pid {pid:#06x})"),
+        };
+        println!("Bus {:03} Device {:03} ID {:04x}:{:04x} {chip}",
+            device.bus_number(),
+            device.address(),
+            device_desc.vendor_id(),
+            device_desc.product_id());
+    }
 
-    while retries > 0 {
-        let fw_header = FWHeader::read(&mut reader)?;
-        println!("[*] FW Header: {fw_header:?}");
-        data_len = fw_header.data_length;
+    Ok(())
+}
 
-        /* CMD 7 don't have data_length filed */
-        if fw_header.dnld_cmd == FW_CMD_7 {
-            data_len = 0;
-        }
-        let mut data_buf = vec![0u8; data_len as usize];
-        reader.read_exact(&mut data_buf)?;
+/** Open, claim and handshake with the selected device through `probe_device`, printing what it found */
+fn probe(filter: &DeviceFilter, opts: &DownloadOptions) -> Result<(), FwError> {
+    let device = single_device(filter, find_devices(filter)?)?;
+    let chip = detect_chip(filter, device.device_descriptor()?.product_id())?;
+    let report = probe_device(chip, &device, opts)?;
+
+    let state = match report.boot_state {
+        Some(BootState::FwDownload) => "download mode",
+        Some(BootState::FwReady) => "firmware running",
+        None => "boot state unknown",
+    };
+    let rev = match report.chip_rev {
+        Some(rev) => rev.to_string(),
+        None => "not queried".to_string(),
+    };
+    println!("Bus {:03} Device {:03} {chip:?}, {state}, chip rev {rev}, bulk OUT {:#04x} IN {:#04x}, interface {} claimed and released",
+        device.bus_number(),
+        device.address(),
+        report.ep_out,
+        report.ep_in,
+        opts.interface);
 
-        // Prepare fw block to send
-        let fw_data = FWData {
-            fw_header: fw_header.clone(),
-            seq_num
-        };
-        
-        while retries > 0 {
-            // Send block
-            println!("[*] Sending packet, seq: {seq_num}");
-            let mut send_buffer = vec![];
-            let mut writer = Cursor::new(&mut send_buffer);
-            // Write fw header + sequence
-            fw_data.write(&mut writer)?;
-
-            // Append data portion
-            writer.write(&data_buf)?;
-            if let Err(_) = handle.write_bulk(0x01, &send_buffer, DRIVER_USB_BULK_MSG_TIMEOUT) {
-                println!("[-] Failed when sending packet...");
-                retries -= 1;
-                sleep(Duration::from_millis(100));
-                continue;
-            }
+    Ok(())
+}
 
-            // Receive sync response
-            let mut recv_buffer = vec![0u8; FW_DNLD_RX_BUF_SIZE];
-            
-            if let Err(_) = handle.read_bulk(0x81, &mut recv_buffer, DRIVER_USB_BULK_MSG_TIMEOUT) {
-                println!("[-] Failed when receiving packet...");
-                retries -= 1;
-                sleep(Duration::from_millis(100));
-                continue;
+/** Descriptor tree of the selected device: device, configurations, interfaces with their alternate settings, endpoints */
+fn print_descriptors(filter: &DeviceFilter) -> Result<(), FwError> {
+    let device = single_device(filter, find_devices(filter)?)?;
+    let desc = device.device_descriptor()?;
+    let active = device.active_config_descriptor().ok().map(|config| config.number());
+
+    println!("Bus {:03} Device {:03} ID {:04x}:{:04x}", device.bus_number(), device.address(), desc.vendor_id(), desc.product_id());
+    println!("  USB {}, device version {}, class {:#04x}/{:#04x}/{:#04x}, ep0 max packet {}",
+        desc.usb_version(),
+        desc.device_version(),
+        desc.class_code(),
+        desc.sub_class_code(),
+        desc.protocol_code(),
+        desc.max_packet_size());
+
+    for index in 0..desc.num_configurations() {
+        let config = device.config_descriptor(index)?;
+        let marker = if Some(config.number()) == active { " (active)" } else { "" };
+        println!("  Configuration {}{marker}: {} interfaces, max power {}mA{}",
+            config.number(),
+            config.num_interfaces(),
+            config.max_power(),
+            if config.self_powered() { ", self powered" } else { "" });
+
+        for iface in config.interfaces() {
+            for setting in iface.descriptors() {
+                println!("    Interface {} alt {}: class {:#04x}/{:#04x}/{:#04x}, {} endpoints",
+                    setting.interface_number(),
+                    setting.setting_number(),
+                    setting.class_code(),
+                    setting.sub_class_code(),
+                    setting.protocol_code(),
+                    setting.num_endpoints());
+
+                for ep in setting.endpoint_descriptors() {
+                    println!("      Endpoint {:#04x} {:?} {:?}, max packet {}, interval {}",
+                        ep.address(),
+                        ep.direction(),
+                        ep.transfer_type(),
+                        ep.max_packet_size(),
+                        ep.interval());
+                }
             }
+        }
+    }
 
-            let sync_header = FWSyncHeader::read(&mut Cursor::new(&recv_buffer))?;
-            println!("[*] Sync header: {sync_header:?}");
-        
-            if sync_header.cmd > 0 {
-                return Err("FW received block with CRC error".into());
-            }
-            else if sync_header.seq_num != seq_num {
-                return Err(format!("Mismatch in seq, got {}, expected: {seq_num}", sync_header.seq_num).into());
-            }
-            else if fw_header.dnld_cmd == FW_HAS_LAST_BLOCK {
-                println!("[+] Last block - finished!");
-                return Ok(());
-            }
+    Ok(())
+}
 
-            // Block transmitted successfully, reset retry count
-            retries = MAX_FW_RETRY;
-            break;
+/** Every Marvell device passing the filter */
+fn find_devices(filter: &DeviceFilter) -> Result<Vec<rusb::Device<rusb::GlobalContext>>, FwError> {
+    let found = rusb::devices()?.iter().filter(|device| filter.matches(device)).collect();
+    Ok(found)
+}
+
+/** Rescan the bus until at least one device shows up, a zero `wait` scans once */
+fn wait_for_devices(filter: &DeviceFilter, wait: Duration, abort: Option<&AtomicBool>) -> Result<Vec<rusb::Device<rusb::GlobalContext>>, FwError> {
+    let start = Instant::now();
+    let mut attempt = 1;
+    info!("Scanning for vendor id {:#06x}", filter.vendor);
+
+    loop {
+        check_interrupted(abort)?;
+        debug!("Scanning for marvell device, attempt {attempt}");
+        let devices = find_devices(filter)?;
+        if !devices.is_empty() || start.elapsed() >= wait {
+            return Ok(devices);
         }
-        seq_num += 1;
+        sleep(DEVICE_POLL_INTERVAL);
+        attempt += 1;
     }
+}
 
-    return Err("Fw download did not succeed".into());
+/**
+ * Rescan until a matching device shows up with a download-mode pid, the previous `--repeat`
+ * iteration left it running the firmware under its runtime pid
+ */
+fn wait_for_download_mode(filter: &DeviceFilter, wait: Duration, abort: Option<&AtomicBool>) -> Result<rusb::Device<rusb::GlobalContext>, FwError> {
+    let start = Instant::now();
+    info!("Waiting up to {}s for the device to return to download mode", wait.as_secs());
+
+    loop {
+        check_interrupted(abort)?;
+        let devices: Vec<_> = find_devices(filter)?.into_iter().filter(|device| {
+            device.device_descriptor().is_ok_and(|desc| filter.pid.is_some() || MarvellChip::from_product_id(desc.product_id()).is_some())
+        }).collect();
+        if !devices.is_empty() {
+            return single_device(filter, devices);
+        }
+        if start.elapsed() >= wait {
+            return Err(no_device_error(filter));
+        }
+        sleep(DEVICE_POLL_INTERVAL);
+    }
+}
+
+/** The only device of a scan, flashing the first of several could hit the wrong adapter */
+fn single_device<T: UsbContext>(filter: &DeviceFilter, devices: Vec<rusb::Device<T>>) -> Result<rusb::Device<T>, FwError> {
+    if devices.len() > 1 {
+        let devices = devices.iter()
+            .map(|device| (device.bus_number(), device.address(), device.device_descriptor().map(|desc| desc.product_id()).unwrap_or_default()))
+            .collect();
+        return Err(FwError::AmbiguousDevice { devices });
+    }
+    devices.into_iter().next().ok_or_else(|| no_device_error(filter))
 }
 
-fn check_chip_rev<T: rusb::UsbContext>(handle: &rusb::DeviceHandle<T>) -> Result<(), Box<dyn std::error::Error>> {
-    let extend = (EXTEND_HDR << 16) | EXTEND_V1;
-    let send_buf = vec![0u8; CHIP_REV_TX_BUF_SIZE];
-    let mut recv_buf = vec![0u8; CHIP_REV_RX_BUF_SIZE];
-    handle.write_bulk(0x01, &send_buf, DRIVER_USB_BULK_MSG_TIMEOUT)?;
-    handle.read_bulk(0x81, &mut recv_buf, DRIVER_USB_BULK_MSG_TIMEOUT)?;
+fn no_device_error(filter: &DeviceFilter) -> FwError {
+    if filter.is_set() {
+        return FwError::NoMatchingDevice { bus: filter.bus, address: filter.address, pid: filter.pid };
+    }
+    FwError::DeviceNotFound
+}
+
+/** Parse and CRC check the whole image, then print a summary */
+fn dry_run(fw_path: &str, opts: &DownloadOptions) -> Result<(), FwError> {
+    let fw = load_fw(fw_path, opts)?;
+    for (seq, block) in (0u32..).zip(fw.blocks()) {
+        block.verify_crc(seq, opts.crc_variant)?;
+    }
+
+    println!("{fw_path}: {} blocks, {} data bytes, last block is seq {}", fw.blocks().len(), fw.total_data_len(), fw.last_block_index());
+
+    Ok(())
+}
 
-    let pkt = UsbAckPkt::read(&mut Cursor::new(&mut recv_buf))?;
-    println!("[*] Chiprev resp: {pkt:?}");
-    
-    if pkt.extend == extend {
-        println!("[*] Chip Rev: {} (From Response)", pkt.chip_rev);
-    } else {
-        println!("[*] Chip Rev: {}", USB8797_A0);
+/** Print every block header next to the CRC computed over its data */
+fn inspect(fw_path: &str, opts: &DownloadOptions) -> Result<(), FwError> {
+    let fw = read_fw_checked(fw_path, opts)?;
+    let crc_variant = opts.crc_variant;
+    let blocks = parse_fw_blocks(&fw)?;
+
+    println!("{:>5}  {:>10}  {:>10}  {:>11}  {:>10}  {:>10}  crc ok", "seq", "dnld_cmd", "base_addr", "data_length", "crc", "computed");
+    let mut mismatches = 0;
+    for (seq, block) in (0u32..).zip(blocks.iter()) {
+        let header = &block.header;
+        let (computed, crc_ok) = match block.verify_crc(seq, crc_variant) {
+            Ok(()) if !block.has_crc() => ("-".to_string(), "-"),
+            Ok(()) => (format!("{:#010x}", header.crc), "yes"),
+            Err(_) => {
+                mismatches += 1;
+                (format!("{:#010x}", crc_variant.compute(&block.data)), "NO")
+            },
+        };
+        println!("{seq:>5}  {:>#10x}  {:>#10x}  {:>11}  {:#010x}  {computed:>10}  {crc_ok}",
+            header.dnld_cmd,
+            header.base_addr,
+            header.data_length,
+            header.crc);
     }
 
+    let data_bytes: usize = blocks.iter().map(|block| block.data.len()).sum();
+    println!("{fw_path}: {} blocks, {data_bytes} data bytes, {mismatches} CRC mismatches", blocks.len());
+
     Ok(())
 }
 
-fn download_fw<T: rusb::UsbContext>(chip: MarvellChip, device: rusb::Device<T>, fw_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("[+] Starting fw download for {:?}", chip);
-    let fw = read_fw(fw_path)?;
-    println!("[+] Read fw {fw_path} ({} bytes)", fw.len());
+/** Outcome of a flash run, printed by `--json`. Fields stay `None` if the run failed before reaching them */
+#[derive(Debug, Default, Serialize)]
+struct JsonReport {
+    /** 1-based `--repeat` iteration, left out otherwise */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iteration: Option<u32>,
+    bus: Option<u8>,
+    address: Option<u8>,
+    chip: Option<String>,
+    chip_rev: Option<String>,
+    blocks_sent: Option<u32>,
+    total_bytes: Option<u64>,
+    retries_used: Option<u32>,
+    elapsed_ms: Option<u128>,
+    /** Device was already running its firmware, nothing was sent */
+    skipped: bool,
+    success: bool,
+    error: Option<String>,
+    /** `exit_code` of the error, the process exits with the first failed report's */
+    #[serde(skip)]
+    exit_code: i32,
+}
 
-    let mut handle = device.open()?;
+impl JsonReport {
+    fn finish(&mut self, result: &Result<(), FwError>) {
+        self.success = result.is_ok();
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        self.exit_code = result.as_ref().err().map_or(0, exit_code);
+    }
+}
 
-    // Ignore error, windows will throw one
-    let _ = handle.set_auto_detach_kernel_driver(true);
+/** Exit code of the first failed download among `reports`, 0 if all succeeded */
+fn reports_exit_code(reports: &[JsonReport]) -> i32 {
+    match reports.iter().find(|report| !report.success && report.exit_code != 0) {
+        Some(report) => report.exit_code,
+        None if reports.iter().any(|report| !report.success) => 1,
+        None => 0,
+    }
+}
 
-    handle.claim_interface(0)?;
+/**
+ * Process exit code for `e`, so scripts can tell failure classes apart.
+ * See `cli::EXIT_CODES_HELP` for the table
+ */
+fn exit_code(e: &FwError) -> i32 {
+    match e {
+        FwError::Interrupted => EXIT_INTERRUPTED,
+        FwError::DeviceNotFound | FwError::NoMatchingDevice { .. } | FwError::AmbiguousDevice { .. } | FwError::UnhandledProductId(_) => 2,
+        // Wrapping errors take the class of what went wrong in the end
+        FwError::RetriesExhausted { last_error: Some(e), .. } | FwError::ChipRevFailed { last_error: e, .. } => exit_code(e),
+        FwError::UsbWrite(_) | FwError::UsbRead(_) | FwError::Usb(_) | FwError::DeviceDisconnected(_) | FwError::ShortWrite { .. }
+            | FwError::NoSuchInterface(_) | FwError::NoSuchAltSetting { .. } | FwError::InterfaceBusy(_) | FwError::EndpointDirection { .. }
+            | FwError::RetriesExhausted { last_error: None, .. } | FwError::DeadlineExceeded { .. } | FwError::Stalled { .. } | FwError::NotReady { .. }
+            | FwError::UnknownRuntimePid(_) | FwError::RebootNotConfirmed { .. } => 3,
+        FwError::InvalidFirmware { .. } | FwError::NoFirmwareInDir { .. } | FwError::DigestMismatch { .. } | FwError::MissingLastBlock
+            | FwError::TruncatedBlock { .. } | FwError::EmptyBlock { .. } | FwError::BlockTooLarge { .. } | FwError::BlockCrcMismatch { .. }
+            | FwError::SeqOverflow | FwError::StartSeqOutOfRange { .. } | FwError::Io(_) | FwError::Parse(_) => 4,
+        FwError::SyncError { .. } | FwError::SeqMismatch { .. } | FwError::ShortResponse { .. } | FwError::StrictViolation(_) => 5,
+    }
+}
 
-    check_chip_rev(&handle)?;
-    program_fw(&handle, &fw)?;
+/**
+ * Chip behind a download-mode or runtime `pid`, unknown ones are only handled generically when
+ * picked explicitly with `--pid`
+ */
+fn detect_chip(filter: &DeviceFilter, pid: u16) -> Result<MarvellChip, FwError> {
+    match MarvellChip::from_product_id(pid).or_else(|| MarvellChip::from_runtime_product_id(pid)) {
+        Some(chip) => Ok(chip),
+        None if filter.pid.is_some() => Ok(MarvellChip::Generic(pid)),
+        None => Err(FwError::UnhandledProductId(pid)),
+    }
+}
 
-    handle.release_interface(0)?;
+fn flash_device<T: UsbContext>(args: &Args, device: rusb::Device<T>, fw: Option<&[Firmware]>, report: &mut JsonReport) -> Result<(), FwError> {
+    let device_desc = device.device_descriptor()?;
+    info!("Found marvell device: Bus {:03} Device {:03} ID {:04x}:{:04x}",
+        device.bus_number(),
+        device.address(),
+        device_desc.vendor_id(),
+        device_desc.product_id());
+    report.bus = Some(device.bus_number());
+    report.address = Some(device.address());
+
+    // A runtime pid means the firmware already runs, download_fw then skips the device
+    let chip = detect_chip(&args.filter, device_desc.product_id())?;
+
+    info!("{chip:?}");
+    report.chip = Some(format!("{chip:?}"));
+    let bus = device.bus_number();
+    let context = device.context().clone();
+    let source = match (fw, args.fw_paths.as_slice(), &args.fw_dir) {
+        (Some([fw]), _, _) => FwSource::Loaded(fw),
+        (Some(fws), _, _) => FwSource::Sequence(fws),
+        (None, [path], _) => FwSource::File(path),
+        (None, [], Some(dir)) => FwSource::Dir(dir),
+        (None, _, _) => unreachable!("clap requires a firmware source, several files are loaded up front"),
+    };
+    let mut download = DownloadReport::default();
+    let result = download_fw_with_report(chip, device, source, &args.opts, &mut download);
+    // Also on failure, retries hint at a flaky link
+    report.retries_used = Some(download.stats.retries_used);
+    result?;
+    report.chip_rev = download.chip_rev.map(|rev| rev.to_string());
+    report.blocks_sent = Some(download.stats.blocks_sent);
+    report.total_bytes = Some(download.stats.bytes_sent);
+    report.elapsed_ms = Some(download.elapsed.as_millis());
+    report.skipped = download.skipped;
+    if download.skipped {
+        return Ok(());
+    }
 
-    //handle.reset()?;
+    if let Some(window) = args.wait_ready {
+        // Only reports what it saw, --verify is the check that fails the download
+        match chip.runtime_product_id() {
+            Some(_) => match wait_boot_state(&context, device_desc.vendor_id(), chip, bus, window, args.opts.abort.as_deref())? {
+                Some(BootState::FwReady) => {},
+                state => warn!("{}", FwError::NotReady { state, waited: window }),
+            },
+            None => warn!("Runtime pid of {chip:?} is unknown, can't confirm the firmware booted"),
+        }
+    }
 
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        return Err(format!("Usage: {} [fw filepath]", &args.first().unwrap()).into());
-    }
-
-    let fw_filepath = &args[1];
-
-    for device in rusb::devices().unwrap().iter() {
-        let device_desc = device.device_descriptor().unwrap();
-        if device_desc.vendor_id() == 0x1286 {
-            println!("[*] Found marvell device: Bus {:03} Device {:03} ID {:04x}:{:04x}",
-                device.bus_number(),
-                device.address(),
-                device_desc.vendor_id(),
-                device_desc.product_id());
-
-            let chip = match device_desc.product_id() {
-                0x2040 => {
-                    MarvellChip::Avastar88W8782U
-                },
-                0x2045 => {
-                    MarvellChip::Avastar88W8897
-                },
-                pid => {
-                    return Err(format!("Unhandled marvell device with pid: {:#X}", pid).into());
+/** Open `path` for appending, a new or empty file gets the CSV header first */
+fn open_sync_log(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(b"timestamp_ms,seq,cmd\n")?;
+    }
+    Ok(file)
+}
+
+/** Whether Ctrl-C was hit, batch modes start nothing new then */
+fn interrupted(args: &Args) -> bool {
+    check_interrupted(args.opts.abort.as_deref()).is_err()
+}
+
+/** `FwError::Interrupted` once Ctrl-C was hit, for the loops waiting on the bus */
+fn check_interrupted(abort: Option<&AtomicBool>) -> Result<(), FwError> {
+    match abort.is_some_and(|abort| abort.load(Ordering::Relaxed)) {
+        true => Err(FwError::Interrupted),
+        false => Ok(()),
+    }
+}
+
+/** Every `--fw` file, loaded and checked in command line order */
+fn load_images(args: &Args) -> Result<Vec<Firmware>, FwError> {
+    args.fw_paths.iter().map(|path| load_fw(path, &args.opts)).collect()
+}
+
+fn run(args: &Args, report: &mut JsonReport) -> Result<(), FwError> {
+    let device = single_device(&args.filter, wait_for_devices(&args.filter, args.wait, args.opts.abort.as_deref())?)?;
+    let images = match args.fw_paths.len() {
+        0 | 1 => None,
+        _ => Some(load_images(args)?),
+    };
+    flash_device(args, device, images.as_deref(), report)
+}
+
+/** Flash the device at `bus`/`address` as enumerated through a worker's own `context` */
+fn flash_target(args: &Args, context: &rusb::Result<rusb::Context>, fw: Option<&[Firmware]>, (bus, address): (u8, u8)) -> JsonReport {
+    DEVICE_TAG.set(Some((bus, address)));
+
+    let mut report = JsonReport::default();
+    let result = context.as_ref().map_err(|e| FwError::Usb(*e))
+        .and_then(|context| {
+            context.devices()?.iter()
+                .find(|device| device.bus_number() == bus && device.address() == address)
+                // Unplugged between the scan and this worker getting to it
+                .ok_or(FwError::NoMatchingDevice { bus: Some(bus), address: Some(address), pid: None })
+        })
+        .and_then(|device| flash_device(args, device, fw, &mut report));
+    if let Err(e) = &result {
+        error!("Firmware download failed: {e}");
+    }
+    report.bus = Some(bus);
+    report.address = Some(address);
+    report.finish(&result);
+
+    DEVICE_TAG.set(None);
+    report
+}
+
+/**
+ * Flash every matching device on up to `--jobs` threads. After a failure no further devices are
+ * started unless `--keep-going`, the ones left out are reported as skipped.
+ * Each worker owns its own libusb context and opens its devices through it.
+ * Reports come back in bus scan order
+ */
+fn run_all(args: &Args) -> Result<Vec<JsonReport>, FwError> {
+    let devices = wait_for_devices(&args.filter, args.wait, args.opts.abort.as_deref())?;
+    if devices.is_empty() {
+        return Err(no_device_error(&args.filter));
+    }
+
+    // Read the files once for all devices, stdin couldn't be read twice anyway
+    let fw = (!args.fw_paths.is_empty()).then(|| load_images(args)).transpose()?;
+    let fw = fw.as_deref();
+
+    let targets: Vec<(u8, u8)> = devices.iter().map(|device| (device.bus_number(), device.address())).collect();
+    let jobs = args.jobs.clamp(1, targets.len());
+    let queue = Mutex::new(targets.into_iter().enumerate());
+    let reports = Mutex::new(Vec::new());
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                let context = rusb::Context::new().map(|mut context| {
+                    if let Some(level) = args.libusb_debug {
+                        context.set_log_level(level.level());
+                    }
+                    context
+                });
+                loop {
+                    // Downloads already running finish either way
+                    if (failed.load(Ordering::Relaxed) && !args.keep_going) || interrupted(args) {
+                        break;
+                    }
+                    let Some((index, target)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let report = flash_target(args, &context, fw, target);
+                    if !report.success {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    reports.lock().unwrap().push((index, report));
                 }
-            };
+            });
+        }
+    });
+
+    let mut reports = reports.into_inner().unwrap();
+    for (index, (bus, address)) in queue.into_inner().unwrap() {
+        let report = JsonReport { bus: Some(bus), address: Some(address), error: Some("skipped after an earlier failure".into()), ..Default::default() };
+        reports.push((index, report));
+    }
+    reports.sort_by_key(|(index, _)| *index);
+    Ok(reports.into_iter().map(|(_, report)| report).collect())
+}
 
-            println!("[*] {chip:?}");
-            download_fw(chip, device, &fw_filepath)?;
+/**
+ * Flash the first matching device `--repeat` times, waiting for it to come back in download mode
+ * in between. Stops at the first failure unless `--keep-going`
+ */
+fn run_repeat(args: &Args) -> Result<Vec<JsonReport>, FwError> {
+    // Same images every time, stdin couldn't be read twice anyway
+    let fw = (!args.fw_paths.is_empty()).then(|| load_images(args)).transpose()?;
+
+    let mut reports = vec![];
+    for iteration in 1..=args.repeat {
+        info!("Iteration {iteration} of {}", args.repeat);
+        let mut report = JsonReport { iteration: Some(iteration), ..Default::default() };
+        let device = match iteration {
+            1 => single_device(&args.filter, wait_for_devices(&args.filter, args.wait, args.opts.abort.as_deref())?),
+            _ => wait_for_download_mode(&args.filter, args.wait.max(REPEAT_WAIT), args.opts.abort.as_deref()),
+        };
+        let result = device.and_then(|device| flash_device(args, device, fw.as_deref(), &mut report));
+        if let Err(e) = &result {
+            error!("Iteration {iteration} failed: {e}");
+        }
+        report.finish(&result);
+        reports.push(report);
 
-            return Ok(());
+        if result.is_err() && (!args.keep_going || interrupted(args)) {
+            break;
         }
     }
 
-    return Err("No device found!".into());
+    Ok(reports)
+}
+
+/** Per-iteration table and totals of a `--repeat` run */
+fn print_repeat_summary(args: &Args, reports: &[JsonReport]) {
+    println!("{:>9}  {:>7}  {:>10}  result", "iteration", "retries", "time");
+    for report in reports {
+        let time = report.elapsed_ms.map(|ms| format!("{:.2}s", ms as f64 / 1000.0)).unwrap_or("-".into());
+        let status = match &report.error {
+            None => "OK".to_string(),
+            Some(e) => format!("FAILED ({e})"),
+        };
+        println!("{:>9}  {:>7}  {time:>10}  {status}", report.iteration.unwrap_or_default(), report.retries_used.unwrap_or_default());
+    }
+
+    let passed = reports.iter().filter(|report| report.success).count();
+    let retries: u32 = reports.iter().filter_map(|report| report.retries_used).sum();
+    println!("{passed} of {} iterations passed ({} run), {retries} retries in total", args.repeat, reports.len());
+}
+
+fn init_logger(cli: &Cli) {
+    let (json, all) = match &cli.command {
+        Command::Flash(flash) => (flash.json, flash.all),
+        _ => (false, false),
+    };
+
+    let mut logger = match cli.global.log_level() {
+        // Explicit flags win over RUST_LOG
+        Some(level) => {
+            let mut logger = env_logger::Builder::new();
+            logger.filter_level(level);
+            logger
+        },
+        None => {
+            // The JSON report replaces the log lines, RUST_LOG can still bring them back
+            let default_filter = if json { "off" } else { "info" };
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        }
+    };
+    if all {
+        // Keeps interleaved output from concurrent downloads apart
+        logger.format(|buf, record| {
+            let tag = DEVICE_TAG.get().map(|(bus, address)| format!(" {bus:03}:{address:03}")).unwrap_or_default();
+            writeln!(buf, "[{} {:<5} {}{tag}] {}", buf.timestamp(), record.level(), record.target(), record.args())
+        });
+    }
+    logger.init();
+}
+
+fn flash(args: Args) {
+    for (path, region) in args.fw_paths.iter().zip(&args.regions) {
+        info!("{region:?} region: {path}");
+    }
+
+    if args.repeat > 1 {
+        let reports = match run_repeat(&args) {
+            Ok(reports) => reports,
+            Err(e) => {
+                error!("Firmware download failed: {e}");
+                std::process::exit(exit_code(&e));
+            }
+        };
+
+        if args.json {
+            println!("{}", serde_json::to_string(&reports).unwrap());
+        } else {
+            print_repeat_summary(&args, &reports);
+        }
+
+        let code = reports_exit_code(&reports);
+        if code != 0 {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    if args.all {
+        let reports = match run_all(&args) {
+            Ok(reports) => reports,
+            Err(e) => {
+                error!("Firmware download failed: {e}");
+                std::process::exit(exit_code(&e));
+            }
+        };
+
+        if args.json {
+            println!("{}", serde_json::to_string(&reports).unwrap());
+        } else {
+            for report in &reports {
+                let status = match &report.error {
+                    None if report.skipped => "SKIPPED (already running its firmware)".to_string(),
+                    None => "OK".to_string(),
+                    Some(e) => format!("FAILED ({e})"),
+                };
+                println!("Bus {:03} Device {:03}: {status}", report.bus.unwrap_or_default(), report.address.unwrap_or_default());
+            }
+        }
+
+        let code = reports_exit_code(&reports);
+        if code != 0 {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    let mut report = JsonReport::default();
+    let result = run(&args, &mut report);
+
+    if args.json {
+        report.finish(&result);
+        println!("{}", serde_json::to_string(&report).unwrap());
+        if let Err(e) = &result {
+            std::process::exit(exit_code(e));
+        }
+        return;
+    }
+
+    match result {
+        Ok(()) if report.skipped => info!("Nothing to download, the device is already running its firmware"),
+        Ok(()) => info!("Firmware download succeeded"),
+        Err(e) => {
+            error!("Firmware download failed: {e}");
+            std::process::exit(exit_code(&e));
+        }
+    }
+}
+
+fn main() {
+    let cli = cli::parse();
+    init_logger(&cli);
+    if let Some(level) = cli.global.libusb_debug {
+        rusb::set_log_level(level.level());
+    }
+
+    let result = match cli.command {
+        Command::Selftest => match selftest() {
+            true => return,
+            false => std::process::exit(1),
+        },
+        Command::Flash(flash_args) => {
+            let mut args = (*flash_args).into_args(&cli.global);
+            let abort = Arc::new(AtomicBool::new(false));
+            let handler_abort = abort.clone();
+            let handler = ctrlc::set_handler(move || {
+                // A second Ctrl-C doesn't wait for the cleanup
+                if handler_abort.swap(true, Ordering::Relaxed) {
+                    std::process::exit(EXIT_INTERRUPTED);
+                }
+                eprintln!("Interrupted, stopping after the current block");
+            });
+            if let Err(e) = handler {
+                error!("Couldn't install the Ctrl-C handler: {e}");
+            }
+            args.opts.abort = Some(abort);
+            if let Some(path) = &args.sync_log {
+                match open_sync_log(path) {
+                    Ok(file) => args.opts.sync_log = Some(Arc::new(Mutex::new(file))),
+                    Err(e) => {
+                        error!("Couldn't open the sync log {}: {e}", path.display());
+                        std::process::exit(exit_code(&FwError::Io(e)));
+                    },
+                }
+            }
+            return flash(args);
+        },
+        Command::List => list_devices(&cli.global.filter()).map_err(|e| ("Listing devices", e)),
+        Command::PrintDescriptors => print_descriptors(&cli.global.filter()).map_err(|e| ("Reading descriptors", e)),
+        Command::Probe(probe_args) => probe(&cli.global.filter(), &probe_args.download_options(&cli.global)).map_err(|e| ("Probe", e)),
+        Command::DryRun(fw) => dry_run(&fw.fw, &fw.download_options(&cli.global)).map_err(|e| ("Dry run", e)),
+        Command::Inspect(fw) => inspect(&fw.fw, &fw.download_options(&cli.global)).map_err(|e| ("Inspecting firmware", e)),
+    };
+
+    if let Err((what, e)) = result {
+        error!("{what} failed: {e}");
+        std::process::exit(exit_code(&e));
+    }
 }
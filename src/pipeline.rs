@@ -0,0 +1,113 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, mpsc, Condvar, Mutex}, thread};
+
+use log::{debug, info};
+
+use crate::{accept_lost_last_ack, block_buffer, check_abort, check_block_crc, check_sync, is_lost_last_ack, pad_buffer, read_sync, write_block, write_zlp, BulkTransport, DownloadOptions, DownloadStats, Firmware, FwError, TxWindow};
+
+/** Block ready to go out: sequence number, bulk OUT buffer, whether it is the last one */
+type QueuedBlock = (u32, Vec<u8>, bool);
+
+/**
+ * Pipelined `program_fw`: a writer thread stays up to `MVUSB_TX_HIGH_WMARK` blocks ahead of the
+ * sync responses, which are reconciled with the sent blocks by sequence number.
+ *
+ * rusb has no async transfer API, so the pipeline runs on a scoped thread instead of queued URBs,
+ * and sync responses are read one at a time rather than `MVUSB_RX_DATA_URB` in parallel.
+ * A failed block isn't retried since later blocks may already be out. `start_seq`, `crc_variant`,
+ * `allow_crc_mismatch`, `pad_out` and `send_zlp` apply but the retry, backoff, delay, deadline and
+ * stall timeout options don't.
+ * A lost ack for the last block counts as a probable success as in `program_fw`, or fails under `strict`.
+ */
+pub fn program_fw_async<B: BulkTransport + Sync>(handle: &B, fw: &Firmware, opts: &DownloadOptions) -> Result<DownloadStats, FwError> {
+    let skip = opts.start_seq as usize;
+    if skip >= fw.blocks().len() {
+        return Err(FwError::StartSeqOutOfRange { start_seq: opts.start_seq, blocks: fw.blocks().len() });
+    }
+
+    // Build every buffer up front, so the writer only does USB
+    let mut queue: Vec<QueuedBlock> = vec![];
+    for (seq_num, block) in (opts.start_seq..).zip(&fw.blocks()[skip..]) {
+        check_block_crc(block, seq_num, opts)?;
+        let mut buffer = block_buffer(block, seq_num)?;
+        pad_buffer(&mut buffer, seq_num, opts)?;
+        queue.push((seq_num, buffer, block.is_last()));
+    }
+
+    let window = Mutex::new(TxWindow::default());
+    let slot_freed = Condvar::new();
+    let abort = AtomicBool::new(false);
+    let (sent_tx, sent_rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            // Owned by the writer, so if it stops (or panics) the reader's recv() fails instead of blocking
+            let sent_tx = sent_tx;
+            for (seq_num, buffer, last) in &queue {
+                let mut guard = window.lock().unwrap();
+                loop {
+                    if abort.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if guard.try_acquire() {
+                        break;
+                    }
+                    guard = slot_freed.wait(guard).unwrap();
+                }
+                drop(guard);
+
+                debug!("Sending packet, seq: {seq_num}");
+                let result = check_abort(opts)
+                    .and_then(|()| write_block(handle, buffer, opts))
+                    .and_then(|()| if *last { write_zlp(handle, opts) } else { Ok(()) });
+                let failed = result.is_err();
+                if sent_tx.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        let result = receive_acks(handle, &queue, opts, &sent_rx, || {
+            window.lock().unwrap().release();
+            slot_freed.notify_one();
+        });
+
+        // Stops the writer early if the reader bailed out. Set under the lock, or the
+        // writer could check abort, miss the notify and then wait forever
+        {
+            let _guard = window.lock().unwrap();
+            abort.store(true, Ordering::Relaxed);
+        }
+        slot_freed.notify_all();
+        result
+    })
+}
+
+/** Match one sync response to every block the writer reports as sent, in order */
+fn receive_acks<B: BulkTransport>(handle: &B, queue: &[QueuedBlock], opts: &DownloadOptions, sent: &mpsc::Receiver<Result<(), FwError>>, mut release: impl FnMut()) -> Result<DownloadStats, FwError> {
+    let mut stats = DownloadStats::default();
+
+    for (seq_num, buffer, last) in queue {
+        // Never read ahead of the write the ack belongs to
+        match sent.recv() {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(FwError::MissingLastBlock),
+        }
+
+        match read_sync(handle, opts) {
+            Ok((sync_header, recv_buffer)) => check_sync(&sync_header, &recv_buffer, *seq_num)?,
+            Err(e) if *last && is_lost_last_ack(&e) => accept_lost_last_ack(&e, opts)?,
+            Err(e) => return Err(e),
+        }
+        release();
+
+        stats.blocks_sent += 1;
+        stats.bytes_sent += buffer.len() as u64;
+        if *last {
+            info!("Last block - finished!");
+            return Ok(stats);
+        }
+    }
+
+    Err(FwError::MissingLastBlock)
+}
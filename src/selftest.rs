@@ -0,0 +1,167 @@
+use std::io::Cursor;
+
+use binrw::{meta::{ReadEndian, WriteEndian}, BinRead, BinWrite};
+
+use crate::{fw_crc32, fw_crc32_mpeg2, fw_sha256, hexdump, parse_fw_blocks, CrcVariant, Firmware, FWData, FWHeader, FWSyncHeader};
+
+/**
+ * Four synthetic blocks: 11 bytes of data at 0x1000, a CMD 7, 100 bytes at 0x2000
+ * and the last block. Each data block carries its IEEE CRC
+ */
+pub(crate) const SAMPLE_FW: &[u8] = include_bytes!("selftest_fw.bin");
+
+const SAMPLE_SHA256: [u8; 32] = [
+    0x23, 0x66, 0x44, 0x02, 0xc5, 0x3a, 0xfc, 0xa5, 0x02, 0x49, 0x03, 0x5a, 0x46, 0x45, 0x47, 0x09,
+    0xa3, 0x89, 0x61, 0x46, 0x02, 0xdd, 0x30, 0xb5, 0x42, 0xe3, 0x23, 0xc0, 0x08, 0xdc, 0x25, 0x55,
+];
+
+/** Standard check input of the CRC catalogues */
+pub(crate) const CRC_CHECK_INPUT: &[u8] = b"123456789";
+
+fn check_sample_digest() -> Result<(), String> {
+    match fw_sha256(SAMPLE_FW) == SAMPLE_SHA256 {
+        true => Ok(()),
+        false => Err("embedded sample doesn't hash to its known digest".into()),
+    }
+}
+
+fn check_parser() -> Result<(), String> {
+    let blocks = parse_fw_blocks(SAMPLE_FW).map_err(|e| e.to_string())?;
+    let layout: Vec<(u32, u32, usize)> = blocks.iter().map(|block| (block.header.dnld_cmd, block.header.base_addr, block.data.len())).collect();
+    if layout != [(0x1, 0x1000, 11), (0x7, 0, 0), (0x1, 0x2000, 100), (0x4, 0, 0)] {
+        return Err(format!("unexpected blocks (cmd, base_addr, length): {layout:x?}"));
+    }
+    if blocks[0].data != b"hello world" {
+        return Err(format!("block 0 data is {:x?}", blocks[0].data));
+    }
+    match blocks.iter().position(|block| block.is_last()) {
+        Some(3) => Ok(()),
+        index => Err(format!("last block flag found at {index:?}, expected 3")),
+    }
+}
+
+fn check_crc() -> Result<(), String> {
+    let ieee = fw_crc32(CRC_CHECK_INPUT);
+    if ieee != 0xcbf43926 {
+        return Err(format!("IEEE check value is {ieee:#010x}, expected 0xcbf43926"));
+    }
+    let mpeg2 = fw_crc32_mpeg2(CRC_CHECK_INPUT);
+    if mpeg2 != 0x0376e6e7 {
+        return Err(format!("MPEG-2 check value is {mpeg2:#010x}, expected 0x0376e6e7"));
+    }
+
+    let fw = Firmware::from_bytes(SAMPLE_FW).map_err(|e| e.to_string())?;
+    match fw.crc_ok(CrcVariant::Ieee) {
+        true => Ok(()),
+        false => Err("sample block CRCs don't verify".into()),
+    }
+}
+
+fn check_hexdump() -> Result<(), String> {
+    let expected = "00001000: 0100 0000 0010 0000 0b00 0000 8511 4a0d  ..............J.\n\
+                    00001010: 6865 6c6c 6f20 776f 726c 64              hello world\n";
+    let dump = hexdump(&SAMPLE_FW[..27], 0x1000);
+    match dump == expected {
+        true => Ok(()),
+        false => Err(format!("got\n{dump}")),
+    }
+}
+
+/** Decode `bytes` as a `T`, encode it again and require the same bytes back */
+fn roundtrip<T>(bytes: &[u8]) -> Result<T, String>
+where
+    T: for<'a> BinRead<Args<'a> = ()> + for<'a> BinWrite<Args<'a> = ()> + ReadEndian + WriteEndian,
+{
+    let value = T::read(&mut Cursor::new(bytes)).map_err(|e| e.to_string())?;
+    let mut written = vec![];
+    value.write(&mut Cursor::new(&mut written)).map_err(|e| e.to_string())?;
+    match written == bytes {
+        true => Ok(value),
+        false => Err(format!("wrote {written:02x?}, read {bytes:02x?}")),
+    }
+}
+
+/** The wire structs are little-endian whatever the host is, decoded fields and re-encoded bytes must both match */
+fn check_wire_format() -> Result<(), String> {
+    let mut data = SAMPLE_FW[..16].to_vec();
+    data.extend(42u32.to_le_bytes());
+
+    let header: FWHeader = roundtrip(&SAMPLE_FW[..16])?;
+    let fw_data: FWData = roundtrip(&data)?;
+    let sync: FWSyncHeader = roundtrip(&[0x02, 0, 0, 0, 0x07, 0, 0, 0])?;
+
+    let fields = (header.dnld_cmd, header.base_addr, header.data_length, header.crc);
+    if fields != (0x1, 0x1000, 11, 0x0d4a1185) {
+        return Err(format!("FWHeader decoded as {fields:#x?}"));
+    }
+    if fw_data.fw_header.crc != header.crc || fw_data.seq_num != 42 {
+        return Err(format!("FWData decoded as {fw_data:x?}"));
+    }
+    match (sync.cmd, sync.seq_num) {
+        (0x2, 7) => Ok(()),
+        fields => Err(format!("FWSyncHeader decoded as {fields:#x?}")),
+    }
+}
+
+/** `FWData` as `program_fw` sends it: the header fields then seq_num, little-endian, no padding */
+fn check_fw_data_layout() -> Result<(), String> {
+    let fw_data = FWData {
+        fw_header: FWHeader { dnld_cmd: 0x1, base_addr: 0x1000, data_length: 11, crc: 0x0d4a1185 },
+        seq_num: 42,
+    };
+    let expected: [u8; 20] = [
+        0x01, 0x00, 0x00, 0x00,
+        0x00, 0x10, 0x00, 0x00,
+        0x0b, 0x00, 0x00, 0x00,
+        0x85, 0x11, 0x4a, 0x0d,
+        0x2a, 0x00, 0x00, 0x00,
+    ];
+
+    let mut written = vec![];
+    fw_data.write(&mut Cursor::new(&mut written)).map_err(|e| e.to_string())?;
+    match written == expected {
+        true => Ok(()),
+        false => Err(format!("serialized as {written:02x?}")),
+    }
+}
+
+/** One self-test check, `Err` describes what went wrong */
+type Check = fn() -> Result<(), String>;
+
+const CHECKS: [(&str, Check); 6] = [
+    ("sample digest", check_sample_digest),
+    ("block parser", check_parser),
+    ("wire format", check_wire_format),
+    ("FWData layout", check_fw_data_layout),
+    ("CRC", check_crc),
+    ("hexdump", check_hexdump),
+];
+
+/** Run every check against the embedded sample, printing PASS/FAIL for each. Whether all passed */
+pub fn selftest() -> bool {
+    let mut passed = true;
+    for (name, check) in CHECKS {
+        match check() {
+            Ok(()) => println!("PASS {name}"),
+            Err(reason) => {
+                println!("FAIL {name}: {reason}");
+                passed = false;
+            },
+        }
+    }
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selftest_checks_pass() {
+        for (name, check) in CHECKS {
+            if let Err(reason) = check() {
+                panic!("{name}: {reason}");
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::FwError;
+
+/**
+ * Bulk endpoint access needed by the download protocol.
+ * Implemented for `rusb::DeviceHandle`, tests and embedders can supply their own.
+ */
+pub trait BulkTransport {
+    fn write_bulk(&self, ep: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    fn read_bulk(&self, ep: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+}
+
+impl<T: rusb::UsbContext> BulkTransport for rusb::DeviceHandle<T> {
+    fn write_bulk(&self, ep: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        rusb::DeviceHandle::write_bulk(self, ep, buf, timeout)
+    }
+
+    fn read_bulk(&self, ep: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        rusb::DeviceHandle::read_bulk(self, ep, buf, timeout)
+    }
+}
+
+/**
+ * First bulk OUT and bulk IN endpoint addresses of `interface` in the active configuration,
+ * on alternate setting `alt_setting`.
+ */
+pub(crate) fn find_bulk_endpoints<T: rusb::UsbContext>(device: &rusb::Device<T>, interface: u8, alt_setting: u8) -> Option<(u8, u8)> {
+    let config = device.active_config_descriptor().ok()?;
+    let iface = config.interfaces().find(|iface| iface.number() == interface)?;
+    let desc = iface.descriptors().find(|desc| desc.setting_number() == alt_setting)?;
+
+    let mut ep_out = None;
+    let mut ep_in = None;
+    for ep in desc.endpoint_descriptors() {
+        if ep.transfer_type() != rusb::TransferType::Bulk {
+            continue;
+        }
+        match ep.direction() {
+            rusb::Direction::Out => { ep_out.get_or_insert(ep.address()); },
+            rusb::Direction::In => { ep_in.get_or_insert(ep.address()); },
+        }
+    }
+
+    Some((ep_out?, ep_in?))
+}
+
+/** `wMaxPacketSize` of endpoint `address` on `interface`/`alt_setting` in the active configuration */
+pub(crate) fn max_packet_size<T: rusb::UsbContext>(device: &rusb::Device<T>, interface: u8, alt_setting: u8, address: u8) -> Option<u16> {
+    let config = device.active_config_descriptor().ok()?;
+    let iface = config.interfaces().find(|iface| iface.number() == interface)?;
+    let desc = iface.descriptors().find(|desc| desc.setting_number() == alt_setting)?;
+    let ep = desc.endpoint_descriptors().find(|ep| ep.address() == address)?;
+    Some(ep.max_packet_size())
+}
+
+/** Whether the active configuration has an interface numbered `interface` */
+pub(crate) fn has_interface<T: rusb::UsbContext>(device: &rusb::Device<T>, interface: u8) -> rusb::Result<bool> {
+    let config = device.active_config_descriptor()?;
+    let found = config.interfaces().any(|iface| iface.number() == interface);
+    Ok(found)
+}
+
+/** Whether `interface` in the active configuration has an alternate setting numbered `alt_setting` */
+pub(crate) fn has_alt_setting<T: rusb::UsbContext>(device: &rusb::Device<T>, interface: u8, alt_setting: u8) -> rusb::Result<bool> {
+    let config = device.active_config_descriptor()?;
+    let found = config.interfaces()
+        .filter(|iface| iface.number() == interface)
+        .flat_map(|iface| iface.descriptors())
+        .any(|desc| desc.setting_number() == alt_setting);
+    Ok(found)
+}
+
+/**
+ * Interface claimed on an open device. Dropping it releases the interface again and reattaches a
+ * kernel driver it detached, so early returns and errors don't leave the device claimed.
+ * `release` is the regular way out and reports a failed release instead
+ */
+pub(crate) struct ClaimedInterface<T: rusb::UsbContext> {
+    handle: Option<rusb::DeviceHandle<T>>,
+    interface: u8,
+    /** Kernel driver was detached explicitly and gets reattached on drop */
+    detached: bool,
+}
+
+impl<T: rusb::UsbContext> ClaimedInterface<T> {
+    /** Claim `interface`, for `detach` detaching a bound kernel driver first */
+    pub(crate) fn claim(mut handle: rusb::DeviceHandle<T>, interface: u8, detach: bool) -> Result<Self, FwError> {
+        let detached = detach && handle.kernel_driver_active(interface)?;
+        if detached {
+            info!("Detaching kernel driver from interface {interface}");
+            handle.detach_kernel_driver(interface)?;
+        }
+
+        if let Err(e) = handle.claim_interface(interface) {
+            if detached {
+                let _ = handle.attach_kernel_driver(interface);
+            }
+            return Err(match e {
+                // Detaching failed (or was never possible), someone else holds the interface
+                rusb::Error::Busy => FwError::InterfaceBusy(interface),
+                e => FwError::Usb(e),
+            });
+        }
+        Ok(ClaimedInterface { handle: Some(handle), interface, detached })
+    }
+
+    pub(crate) fn handle(&self) -> &rusb::DeviceHandle<T> {
+        self.handle.as_ref().expect("handle is only taken by release")
+    }
+
+    pub(crate) fn handle_mut(&mut self) -> &mut rusb::DeviceHandle<T> {
+        self.handle.as_mut().expect("handle is only taken by release")
+    }
+
+    /**
+     * Release the interface after a finished download and reattach a kernel driver it detached,
+     * the handle stays open for e.g. a reset
+     */
+    pub(crate) fn release(mut self) -> Result<rusb::DeviceHandle<T>, FwError> {
+        let mut handle = self.handle.take().expect("handle is only taken by release");
+        handle.release_interface(self.interface)?;
+        if self.detached {
+            match handle.attach_kernel_driver(self.interface) {
+                // Already dropped off the bus to come back running the firmware
+                Ok(()) | Err(rusb::Error::NoDevice) => {},
+                Err(e) => return Err(FwError::Usb(e)),
+            }
+        }
+        Ok(handle)
+    }
+}
+
+impl<T: rusb::UsbContext> Drop for ClaimedInterface<T> {
+    fn drop(&mut self) {
+        let Some(handle) = self.handle.as_mut() else {
+            return;
+        };
+        if let Err(e) = handle.release_interface(self.interface) {
+            warn!("Couldn't release interface {}: {e}", self.interface);
+        }
+        if self.detached {
+            if let Err(e) = handle.attach_kernel_driver(self.interface) {
+                warn!("Couldn't reattach the kernel driver to interface {}: {e}", self.interface);
+            }
+        }
+    }
+}